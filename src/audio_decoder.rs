@@ -0,0 +1,200 @@
+use std::fmt::Display;
+use std::io::Cursor;
+
+use eyre::{Result, eyre};
+use rodio::source::UniformSourceIterator;
+use rodio::{Decoder, Source};
+
+use crate::audio_sink::{OUTPUT_CHANNELS, OUTPUT_SAMPLE_RATE};
+
+const SUPPORTED_FORMATS: &str = "use MP3, Ogg/Vorbis, WAV or FLAC";
+
+// Container of a clip, sniffed from its first bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioFormat {
+    Mp3,
+    OggVorbis,
+    OggOpus,
+    Ogg,
+    Wav,
+    Flac,
+    WebM,
+    Aac,
+    Unknown,
+}
+
+impl AudioFormat {
+    // Sniffs the container from the magic bytes, the file extension or the declared type are not trusted
+    pub fn sniff(data: &[u8]) -> Self {
+        match data {
+            [b'I', b'D', b'3', ..] => AudioFormat::Mp3,
+            // MPEG audio frame sync, layer bits 00 is AAC in an ADTS stream
+            [0xFF, second, ..] if second & 0xE0 == 0xE0 && second & 0x06 == 0 => AudioFormat::Aac,
+            [0xFF, second, ..] if second & 0xE0 == 0xE0 => AudioFormat::Mp3,
+            [b'O', b'g', b'g', b'S', ..] => {
+                // The first page holds the codec identification header
+                let first_page = &data[..data.len().min(128)];
+                if contains(first_page, b"OpusHead") {
+                    AudioFormat::OggOpus
+                } else if contains(first_page, b"\x01vorbis") {
+                    AudioFormat::OggVorbis
+                } else {
+                    AudioFormat::Ogg
+                }
+            }
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => AudioFormat::Wav,
+            [b'f', b'L', b'a', b'C', ..] => AudioFormat::Flac,
+            [0x1A, 0x45, 0xDF, 0xA3, ..] => AudioFormat::WebM,
+            _ => AudioFormat::Unknown,
+        }
+    }
+
+    // There is no Opus decoder, so Ogg/Opus and WebM clips are rejected
+    pub fn is_supported(&self) -> bool {
+        matches!(
+            self,
+            AudioFormat::Mp3 | AudioFormat::OggVorbis | AudioFormat::Wav | AudioFormat::Flac
+        )
+    }
+}
+
+impl Display for AudioFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            AudioFormat::Mp3 => "MP3",
+            AudioFormat::OggVorbis => "Ogg/Vorbis",
+            AudioFormat::OggOpus => "Ogg/Opus",
+            AudioFormat::Ogg => "Ogg",
+            AudioFormat::Wav => "WAV",
+            AudioFormat::Flac => "FLAC",
+            AudioFormat::WebM => "WebM",
+            AudioFormat::Aac => "AAC",
+            AudioFormat::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+// Largest clip accepted, checked before and while decoding
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeLimits {
+    pub max_bytes: usize,
+    pub max_secs: f32,
+}
+
+// Decodes the clip into interleaved f32 samples at OUTPUT_SAMPLE_RATE / OUTPUT_CHANNELS.
+// The errors are meant to be shown in chat as they are.
+pub fn decode_audio(data: &[u8], limits: &DecodeLimits) -> Result<Vec<f32>> {
+    if data.is_empty() {
+        return Err(eyre!("the audio is empty"));
+    }
+    if data.len() > limits.max_bytes {
+        return Err(eyre!(
+            "the audio is too large, {:.1} MB for a maximum of {:.1} MB",
+            data.len() as f32 / 1_000_000.0,
+            limits.max_bytes as f32 / 1_000_000.0
+        ));
+    }
+
+    let format = AudioFormat::sniff(data);
+    if !format.is_supported() {
+        return Err(eyre!("{} audio is not supported, {}", format, SUPPORTED_FORMATS));
+    }
+
+    let source = Decoder::new(Cursor::new(data.to_vec())).map_err(|e| eyre!("invalid {} audio: {}", format, e))?;
+    if let Some(duration) = source.total_duration()
+        && duration.as_secs_f32() > limits.max_secs
+    {
+        return Err(eyre!(
+            "the audio is too long, {:.0} s for a maximum of {:.0} s",
+            duration.as_secs_f32(),
+            limits.max_secs
+        ));
+    }
+
+    // The duration is not always in the headers, so the decoding stops once past the limit
+    let max_samples = (limits.max_secs * (OUTPUT_SAMPLE_RATE * OUTPUT_CHANNELS as u32) as f32) as usize;
+    let samples = UniformSourceIterator::<_, f32>::new(source, OUTPUT_CHANNELS, OUTPUT_SAMPLE_RATE)
+        .take(max_samples + 1)
+        .collect::<Vec<_>>();
+    if samples.len() > max_samples {
+        return Err(eyre!(
+            "the audio is longer than the maximum of {:.0} s",
+            limits.max_secs
+        ));
+    }
+    log_trace!("Decoded {} audio, {} samples", format, samples.len());
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: DecodeLimits = DecodeLimits {
+        max_bytes: 1024,
+        max_secs: 1.0,
+    };
+
+    #[test]
+    fn sniffs_mp3() {
+        assert_eq!(AudioFormat::sniff(b"ID3\x04\x00"), AudioFormat::Mp3);
+        assert_eq!(AudioFormat::sniff(&[0xFF, 0xFB, 0x90, 0x64]), AudioFormat::Mp3);
+    }
+
+    #[test]
+    fn sniffs_aac_apart_from_mp3() {
+        assert_eq!(AudioFormat::sniff(&[0xFF, 0xF1, 0x50, 0x80]), AudioFormat::Aac);
+    }
+
+    #[test]
+    fn sniffs_the_ogg_codec() {
+        let mut opus = b"OggS\x00\x02".to_vec();
+        opus.extend_from_slice(&[0; 22]);
+        opus.extend_from_slice(b"OpusHead");
+        assert_eq!(AudioFormat::sniff(&opus), AudioFormat::OggOpus);
+
+        let mut vorbis = b"OggS\x00\x02".to_vec();
+        vorbis.extend_from_slice(&[0; 22]);
+        vorbis.extend_from_slice(b"\x01vorbis");
+        assert_eq!(AudioFormat::sniff(&vorbis), AudioFormat::OggVorbis);
+
+        assert_eq!(AudioFormat::sniff(b"OggS\x00\x02"), AudioFormat::Ogg);
+    }
+
+    #[test]
+    fn sniffs_wav_flac_and_webm() {
+        assert_eq!(AudioFormat::sniff(b"RIFF\x24\x00\x00\x00WAVEfmt "), AudioFormat::Wav);
+        assert_eq!(AudioFormat::sniff(b"RIFF\x24\x00\x00\x00AVI "), AudioFormat::Unknown);
+        assert_eq!(AudioFormat::sniff(b"fLaC\x00\x00\x00\x22"), AudioFormat::Flac);
+        assert_eq!(AudioFormat::sniff(&[0x1A, 0x45, 0xDF, 0xA3, 0x9F]), AudioFormat::WebM);
+    }
+
+    #[test]
+    fn sniffs_unknown_data() {
+        assert_eq!(AudioFormat::sniff(b""), AudioFormat::Unknown);
+        assert_eq!(AudioFormat::sniff(b"<html>"), AudioFormat::Unknown);
+    }
+
+    #[test]
+    fn rejects_empty_and_large_clips() {
+        assert_eq!(
+            decode_audio(b"", &LIMITS).unwrap_err().to_string(),
+            "the audio is empty"
+        );
+        let error = decode_audio(&[0; 2048], &LIMITS).unwrap_err().to_string();
+        assert!(error.starts_with("the audio is too large"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_unsupported_formats_with_the_supported_ones() {
+        let error = decode_audio(&[0x1A, 0x45, 0xDF, 0xA3, 0x9F], &LIMITS)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "WebM audio is not supported, use MP3, Ogg/Vorbis, WAV or FLAC");
+    }
+}
@@ -1,233 +1,1090 @@
-use std::io::Cursor;
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, LazyLock};
-use std::thread::sleep;
 use std::time::Duration;
 
-use eyre::Result;
+use eyre::{Result, eyre};
 use futures::executor::block_on;
-use kira::sound::static_sound::StaticSoundData;
-use kira::{AudioManager, AudioManagerSettings, DefaultBackend};
-// compile this only for linux
-#[cfg(target_os = "linux")]
-use psimple::Simple;
-// compile this only for linux
-#[cfg(target_os = "linux")]
-use pulse::sample::{Format, Spec};
-// compile this only for linux
-#[cfg(target_os = "linux")]
-use pulse::stream::Direction;
-#[cfg(target_os = "linux")]
-use rodio::{Decoder, Source};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 use crate::CONFIG_DIR;
-use crate::bot_commands::BOT_COMMANDS;
+use crate::audio_decoder::{DecodeLimits, decode_audio};
+use crate::audio_sink::{AudioBackend, AudioSink, OUTPUT_CHANNELS, OUTPUT_SAMPLE_RATE, create_sink, list_devices};
+use crate::bot_commands::{BOT_COMMANDS, BOT_COMMANDS_CONFIG, CommandSpec, Subcommand};
+use crate::command_args::{ArgKind, ArgSpec, CommandArgs};
 use crate::common::{MSGQueue, PersistentConfig};
-use crate::irc_parser::IrcMessage;
+use crate::irc_parser::{IrcMessage, Role};
+use crate::twitch_client::TWITCH_RECEIVER;
 
-pub static TTS_AUDIO_QUEUE: LazyLock<MSGQueue<Vec<u8>>> = LazyLock::new(|| MSGQueue::new());
+pub static TTS_AUDIO_QUEUE: LazyLock<MSGQueue<AudioClip>> = LazyLock::new(|| MSGQueue::new());
 pub static TTS_AUDIO_CONTROL: LazyLock<AudioPlayControl> = LazyLock::new(|| AudioPlayControl::new());
-pub static AUDIO_CONTROL: LazyLock<AudioControl> = LazyLock::new(|| AudioControl::init(CONFIG_DIR));
+pub static AUDIO_CONTROL: LazyLock<RwLock<AudioControl>> =
+    LazyLock::new(|| RwLock::new(AudioControl::init(CONFIG_DIR)));
+pub static AUDIO_OUTPUT: LazyLock<AudioOutput> = LazyLock::new(|| AudioOutput::start());
+
+// Samples written to the sink at once, between two checks of the player status
+static OUTPUT_CHUNK_SAMPLES: usize = 1024;
+// How long the output waits for the next clip before draining the sink
+static IDLE_DRAIN_DELAY: Duration = Duration::from_millis(250);
+
+// Where a clip comes from, each kind has its own gain and queueing rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioSource {
+    Alert,
+    CommandSound,
+    BotReply,
+    ChatTts,
+}
+
+impl AudioSource {
+    pub fn is_speech(&self) -> bool {
+        matches!(self, AudioSource::BotReply | AudioSource::ChatTts)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioClip {
+    // Decoded interleaved samples in the output format, shared with the replay copy
+    pub samples: Arc<[f32]>,
+    pub source: AudioSource,
+    // Set from the queue rules when the clip is queued
+    pub priority: u8,
+}
+
+impl AudioClip {
+    // Decodes the audio off the async runtime, unsupported or oversized clips are rejected
+    // before they reach the queue
+    pub async fn decode(data: Vec<u8>, source: AudioSource) -> Result<Self> {
+        let limits = AUDIO_CONTROL.read().await.decode_limits();
+        let samples = tokio::task::spawn_blocking(move || decode_audio(&data, &limits)).await??;
+        Ok(Self {
+            samples: samples.into(),
+            source,
+            priority: 0,
+        })
+    }
+}
+
+// What a clip arriving in the queue does to a playing clip of lower priority
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preemption {
+    // Waits for the playing clip to end, then plays before the lower priority queued clips
+    Wait,
+    // Plays right away over the playing clip, which is lowered by duck_db meanwhile
+    Duck,
+    // Drops the playing clip
+    Interrupt,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct QueueRule {
+    // Higher priority clips are played first
+    pub priority: u8,
+    pub preemption: Preemption,
+}
+
+fn default_queue_rules() -> HashMap<AudioSource, QueueRule> {
+    HashMap::from([
+        (AudioSource::Alert, QueueRule {
+            priority: 100,
+            preemption: Preemption::Duck,
+        }),
+        (AudioSource::CommandSound, QueueRule {
+            priority: 50,
+            preemption: Preemption::Wait,
+        }),
+        (AudioSource::BotReply, QueueRule {
+            priority: 30,
+            preemption: Preemption::Wait,
+        }),
+        (AudioSource::ChatTts, QueueRule {
+            priority: 10,
+            preemption: Preemption::Wait,
+        }),
+    ])
+}
+
+fn default_duck_db() -> f32 {
+    -12.0
+}
+
+fn default_fade_ms() -> u32 {
+    150
+}
+
+fn default_max_clip_bytes() -> usize {
+    10_000_000
+}
+
+fn default_max_clip_secs() -> f32 {
+    60.0
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AudioControl {
+    // Master gain in dB, applied on every backend
     volume: f32,
+    // Gains in dB added to the master gain for TTS speech and for sound effects of external commands
+    #[serde(default)]
+    speech_volume: f32,
+    #[serde(default)]
+    effects_volume: f32,
+    // Brings every clip to the same loudness before the gains, so loud meme sounds don't blast the stream
+    #[serde(default = "default_normalize")]
+    normalize: bool,
+    #[serde(default = "default_normalize_target_db")]
+    normalize_target_db: f32,
+    #[serde(default = "default_normalize_max_boost_db")]
+    normalize_max_boost_db: f32,
     linux_sink_name: Option<String>,
+    // cpal output device of the kira backend, the default device when missing
+    #[serde(default)]
+    kira_device_name: Option<String>,
+    // kira, pulse, wav or null. When missing, pulse is used if linux_sink_name is set, kira otherwise
+    #[serde(default)]
+    backend: Option<AudioBackend>,
+    #[serde(default = "default_wav_output_path")]
+    wav_output_path: String,
+    // Priority and preemption of each clip source, missing sources play with priority 0 and wait
+    #[serde(default = "default_queue_rules")]
+    queue_rules: HashMap<AudioSource, QueueRule>,
+    // Gain in dB applied to the clips playing under a higher priority one
+    #[serde(default = "default_duck_db")]
+    duck_db: f32,
+    // Fade applied on pause, resume and stop, and to the duck transitions
+    #[serde(default = "default_fade_ms")]
+    fade_ms: u32,
+    // Overlap between two queued clips, 0 plays them back to back
+    #[serde(default)]
+    crossfade_ms: u32,
+    // Larger or longer clips are rejected before they are queued
+    #[serde(default = "default_max_clip_bytes")]
+    max_clip_bytes: usize,
+    #[serde(default = "default_max_clip_secs")]
+    max_clip_secs: f32,
 }
 
 impl Default for AudioControl {
     fn default() -> Self {
         Self {
             volume: -6.0,
+            speech_volume: 0.0,
+            effects_volume: 0.0,
+            normalize: default_normalize(),
+            normalize_target_db: default_normalize_target_db(),
+            normalize_max_boost_db: default_normalize_max_boost_db(),
             linux_sink_name: None,
+            kira_device_name: None,
+            backend: None,
+            wav_output_path: default_wav_output_path(),
+            queue_rules: default_queue_rules(),
+            duck_db: default_duck_db(),
+            fade_ms: default_fade_ms(),
+            crossfade_ms: 0,
+            max_clip_bytes: default_max_clip_bytes(),
+            max_clip_secs: default_max_clip_secs(),
         }
     }
 }
 
+fn default_wav_output_path() -> String {
+    "audio_output.wav".into()
+}
+
+// Off by default, so the clips keep the loudness they had before normalization existed
+fn default_normalize() -> bool {
+    false
+}
+
+fn default_normalize_target_db() -> f32 {
+    -18.0
+}
+
+fn default_normalize_max_boost_db() -> f32 {
+    12.0
+}
+
 impl AudioControl {
     pub fn init(config_dir: Option<&str>) -> Self {
         block_on(AudioControl::load(config_dir))
     }
 
     pub fn warm_up(&self) {}
+
+    pub fn backend(&self) -> AudioBackend {
+        match (self.backend, &self.linux_sink_name) {
+            (Some(backend), _) => backend,
+            (None, Some(_)) => AudioBackend::Pulse,
+            (None, None) => AudioBackend::Kira,
+        }
+    }
+
+    // Total gain in dB for a clip of the given source
+    pub fn gain_db(&self, source: AudioSource) -> f32 {
+        self.volume
+            + match source {
+                source if source.is_speech() => self.speech_volume,
+                _ => self.effects_volume,
+            }
+    }
+
+    pub fn queue_rule(&self, source: AudioSource) -> QueueRule {
+        self.queue_rules.get(&source).copied().unwrap_or(QueueRule {
+            priority: 0,
+            preemption: Preemption::Wait,
+        })
+    }
+
+    pub fn decode_limits(&self) -> DecodeLimits {
+        DecodeLimits {
+            max_bytes: self.max_clip_bytes,
+            max_secs: self.max_clip_secs,
+        }
+    }
+
+    fn mix_settings(&self) -> MixSettings {
+        MixSettings {
+            fade_samples: ms_to_samples(self.fade_ms),
+            crossfade_samples: ms_to_samples(self.crossfade_ms),
+            duck_gain: db_to_amplitude(self.duck_db),
+        }
+    }
+
+    // Output device of the active backend, None for the default device
+    pub fn device_name(&self) -> Option<String> {
+        match self.backend() {
+            AudioBackend::Kira => self.kira_device_name.clone(),
+            AudioBackend::Pulse => self.linux_sink_name.clone(),
+            AudioBackend::Wav | AudioBackend::Null => None,
+        }
+    }
+
+    pub fn set_device_name(&mut self, device: Option<String>) {
+        match self.backend() {
+            AudioBackend::Kira => self.kira_device_name = device,
+            // Keeps the pulse backend selected when the sink is reset to the default
+            AudioBackend::Pulse => {
+                self.backend = Some(AudioBackend::Pulse);
+                self.linux_sink_name = device;
+            }
+            AudioBackend::Wav | AudioBackend::Null => {}
+        }
+    }
+
+    pub fn wav_output_path(&self) -> &str {
+        &self.wav_output_path
+    }
 }
 
 impl PersistentConfig for AudioControl {}
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum PlayerCommands {
-    Play,
-    Stop,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlayerState {
+    // Waiting for the next clip
     Ready,
-    Busy,
+    Playing,
+    // Holds the current clip, or the next one when paused while idle
+    Paused,
+    // The current clip is being dropped, by !stop, !skip or !clear
+    Stopping,
 }
 
+// Transport control of the audio player. Every transition is a single atomic update of the watch channel,
+// and the output thread waits on it instead of polling.
 pub struct AudioPlayControl {
-    status: Arc<RwLock<PlayerCommands>>,
-    notify: Arc<tokio::sync::Notify>,
+    state: tokio::sync::watch::Sender<PlayerState>,
+    last_clip: RwLock<Option<AudioClip>>,
 }
 
 impl AudioPlayControl {
     pub fn new() -> Self {
         Self {
-            status: Arc::new(RwLock::new(PlayerCommands::Ready)),
-            notify: Arc::new(tokio::sync::Notify::new()),
+            state: tokio::sync::watch::Sender::new(PlayerState::Ready),
+            last_clip: RwLock::new(None),
         }
     }
 
-    pub async fn set_status_play(&self) {
-        *self.status.write().await = PlayerCommands::Play;
-        self.notify.notify_one();
+    pub fn state(&self) -> PlayerState {
+        *self.state.borrow()
+    }
+
+    pub fn subscribe(&self) -> tokio::sync::watch::Receiver<PlayerState> {
+        self.state.subscribe()
     }
 
-    pub async fn set_status_stop(&self) {
-        if self.get_status().await != PlayerCommands::Busy {
-            log_debug!("Audio is not playing");
-            return;
+    // Applies the transition if it is valid from the current state, returns whether it was applied
+    fn transition(&self, next: impl FnOnce(PlayerState) -> Option<PlayerState>) -> bool {
+        self.state.send_if_modified(|state| match next(*state) {
+            Some(new_state) if new_state != *state => {
+                log_trace!("Audio player {:?} -> {:?}", state, new_state);
+                *state = new_state;
+                true
+            }
+            _ => false,
+        })
+    }
+
+    pub async fn begin_clip(&self, clip: &AudioClip) {
+        *self.last_clip.write().await = Some(clip.clone());
+        self.transition(|state| (state == PlayerState::Ready).then_some(PlayerState::Playing));
+    }
+
+    pub fn end_clip(&self) {
+        self.transition(|state| (state != PlayerState::Paused).then_some(PlayerState::Ready));
+    }
+
+    // Priority of the clip being played, None when idle
+    pub async fn playing_priority(&self) -> Option<u8> {
+        match self.state() {
+            PlayerState::Playing | PlayerState::Paused => {
+                self.last_clip.read().await.as_ref().map(|clip| clip.priority)
+            }
+            _ => None,
         }
-        *self.status.write().await = PlayerCommands::Stop;
-        self.notify.notify_one();
     }
 
-    pub async fn set_status_busy(&self) {
-        log_trace!("Audio player set to busy");
-        *self.status.write().await = PlayerCommands::Busy
+    pub fn pause(&self) -> bool {
+        self.transition(|state| {
+            matches!(state, PlayerState::Ready | PlayerState::Playing).then_some(PlayerState::Paused)
+        })
     }
 
-    pub async fn set_status_ready(&self) {
-        *self.status.write().await = PlayerCommands::Ready
+    pub fn resume(&self) -> bool {
+        self.transition(|state| (state == PlayerState::Paused).then_some(PlayerState::Playing))
     }
 
-    pub async fn get_event(&self) -> PlayerCommands {
-        self.notify.notified().await;
-        let event = self.status.read().await.clone();
-        event
+    // Drops the current clip, the queue goes on with the next one
+    pub fn skip(&self) -> bool {
+        self.transition(|state| {
+            matches!(state, PlayerState::Playing | PlayerState::Paused).then_some(PlayerState::Stopping)
+        })
     }
 
-    pub async fn get_status(&self) -> PlayerCommands {
-        self.status.read().await.clone()
+    // Drops the current clip and every queued one, returns how many were queued
+    pub async fn clear(&self) -> usize {
+        let cleared = TTS_AUDIO_QUEUE.clear().await;
+        self.skip();
+        cleared
+    }
+
+    // Queues the last played clip to play next
+    pub async fn replay(&self) -> bool {
+        match self.last_clip.read().await.clone() {
+            Some(clip) => {
+                TTS_AUDIO_QUEUE.push_front(clip).await;
+                true
+            }
+            None => false,
+        }
     }
 }
 
+// Owns one long-lived output device on a dedicated thread, clips are mixed into it.
+// Audio devices are not Send on every platform, so they never leave the output thread.
+pub struct AudioOutput {
+    requests: Sender<OutputMessage>,
+}
+
+enum OutputMessage {
+    Play(OutputRequest),
+    // Recreates the sink from AudioControl, after a device change
+    Reopen,
+}
+
+struct OutputRequest {
+    samples: Vec<f32>,
+    priority: u8,
+    // Played over the queue, like a ducking alert, and not dropped by !skip
+    overlay: bool,
+    settings: MixSettings,
+    done: tokio::sync::oneshot::Sender<Result<()>>,
+}
+
+impl AudioOutput {
+    fn start() -> Self {
+        let (requests, receiver) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("audio-output".into())
+            .spawn(move || output_thread(receiver))
+            .expect("Unable to spawn the audio output thread");
+        Self { requests }
+    }
+
+    // Mixes the clip into the output and resolves when it is finished or stopped,
+    // or when the crossfade with the next clip may start
+    pub async fn play(&self, clip: AudioClip) -> Result<()> {
+        self.mix(clip, false).await
+    }
+
+    // Mixes the clip over the queued ones, it plays to its end whatever the transport does
+    pub async fn play_over(&self, clip: AudioClip) -> Result<()> {
+        self.mix(clip, true).await
+    }
+
+    async fn mix(&self, clip: AudioClip, overlay: bool) -> Result<()> {
+        let config = AUDIO_CONTROL.read().await.clone();
+        let settings = config.mix_settings();
+        let priority = clip.priority;
+        // The gain stage goes through every sample, it is kept off the async runtime and off the output thread
+        let samples = tokio::task::spawn_blocking(move || {
+            let mut samples = clip.samples.to_vec();
+            apply_gain(&mut samples, &config, clip.source);
+            samples
+        })
+        .await?;
+
+        let (done, result) = tokio::sync::oneshot::channel();
+        self.requests
+            .send(OutputMessage::Play(OutputRequest {
+                samples,
+                priority,
+                overlay,
+                settings,
+                done,
+            }))
+            .map_err(|_| eyre!("Audio output thread is not running"))?;
+        result.await?
+    }
+
+    // Switches to the device now configured in AUDIO_CONTROL, the playing clips go on there
+    pub fn reopen(&self) -> Result<()> {
+        self.requests
+            .send(OutputMessage::Reopen)
+            .map_err(|_| eyre!("Audio output thread is not running"))
+    }
+}
+
+// Mixer settings in samples, taken from AudioControl when a clip is played
+#[derive(Debug, Clone, Copy)]
+struct MixSettings {
+    fade_samples: usize,
+    crossfade_samples: usize,
+    duck_gain: f32,
+}
+
+impl Default for MixSettings {
+    fn default() -> Self {
+        AudioControl::default().mix_settings()
+    }
+}
+
+fn ms_to_samples(ms: u32) -> usize {
+    ms as usize * OUTPUT_SAMPLE_RATE as usize * OUTPUT_CHANNELS as usize / 1000
+}
+
+struct MixerVoice {
+    samples: Vec<f32>,
+    position: usize,
+    priority: u8,
+    overlay: bool,
+    // Current duck amplitude, moving towards the duck gain while a higher priority voice plays
+    duck: f32,
+    // Stop fade amplitude, moving towards 0.0 while the player is stopping
+    fade: f32,
+    // Crossfades in with the clip that was playing when it started
+    fade_in: bool,
+    done: Option<tokio::sync::oneshot::Sender<Result<()>>>,
+}
+
+impl MixerVoice {
+    fn is_playing(&self) -> bool {
+        self.position < self.samples.len()
+    }
+
+    fn finish(&mut self, result: Result<()>) {
+        if let Some(done) = self.done.take() {
+            let _ = done.send(result);
+        }
+    }
+}
+
+// Sums the playing clips, ducking the lower priority ones, with fades on pause, resume and stop.
+// Stop and skip only drop the queued clips, the overlays play to their end.
+#[derive(Default)]
+struct Mixer {
+    voices: Vec<MixerVoice>,
+    settings: MixSettings,
+    // Pause fade amplitude, 0.0 while paused
+    fade: f32,
+}
+
+impl Mixer {
+    fn new() -> Self {
+        Self {
+            fade: 1.0,
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, request: OutputRequest) {
+        self.settings = request.settings;
+        // Nothing to crossfade with after silence
+        let fade_in = !request.overlay
+            && self.settings.crossfade_samples > 0
+            && self.voices.iter().any(|voice| !voice.overlay && voice.is_playing());
+        self.voices.push(MixerVoice {
+            samples: request.samples,
+            position: 0,
+            priority: request.priority,
+            overlay: request.overlay,
+            duck: 1.0,
+            fade: 1.0,
+            fade_in,
+            done: Some(request.done),
+        });
+    }
+
+    fn is_idle(&self) -> bool {
+        self.voices.is_empty()
+    }
+
+    fn is_paused(&self, state: PlayerState) -> bool {
+        state == PlayerState::Paused && self.fade <= 0.0
+    }
+
+    fn fail(&mut self, message: String) {
+        self.voices
+            .drain(..)
+            .for_each(|mut voice| voice.finish(Err(eyre!(message.clone()))));
+    }
+
+    // Whether every queued clip has faded out after a stop
+    fn is_stopped(&self) -> bool {
+        self.voices.iter().all(|voice| voice.overlay || voice.fade <= 0.0)
+    }
+
+    // Mixes up to len samples, less when a pause fades out or the clips end
+    fn mix(&mut self, state: PlayerState, len: usize) -> Vec<f32> {
+        let target = match state {
+            PlayerState::Ready | PlayerState::Playing | PlayerState::Stopping => 1.0,
+            PlayerState::Paused => 0.0,
+        };
+        // A clip starting while paused waits silently instead of fading out
+        if target == 0.0 && self.voices.iter().all(|voice| voice.position == 0) {
+            self.fade = 0.0;
+        }
+        let stopping = state == PlayerState::Stopping;
+        if stopping {
+            // A clip starting while stopping, or paused before the stop, is dropped without being heard
+            let paused = self.fade <= 0.0;
+            self.voices
+                .iter_mut()
+                .filter(|voice| !voice.overlay && (paused || voice.position == 0))
+                .for_each(|voice| voice.fade = 0.0);
+        }
+        let step = 1.0 / self.settings.fade_samples.max(1) as f32;
+        let crossfade = self.settings.crossfade_samples;
+        let top_priority = self.voices.iter().map(|voice| voice.priority).max().unwrap_or_default();
+        // The newest queued clip, the older ones fade out while it fades in
+        let newest = self.voices.iter().rposition(|voice| !voice.overlay);
+
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len && self.voices.iter().any(MixerVoice::is_playing) {
+            self.fade = move_towards(self.fade, target, step);
+            if (target == 0.0 && self.fade <= 0.0) || (stopping && self.is_stopped()) {
+                break;
+            }
+            let fade_in = newest
+                .map(|index| &self.voices[index])
+                .filter(|voice| voice.fade_in)
+                .map(|voice| (voice.position as f32 / crossfade as f32).min(1.0));
+            let mut sample = 0.0;
+            for (index, voice) in self
+                .voices
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, voice)| voice.is_playing())
+            {
+                let duck_target = if voice.priority < top_priority {
+                    self.settings.duck_gain
+                } else {
+                    1.0
+                };
+                voice.duck = move_towards(voice.duck, duck_target, step);
+                if stopping && !voice.overlay {
+                    voice.fade = move_towards(voice.fade, 0.0, step);
+                }
+                let envelope = match (voice.overlay, Some(index) == newest) {
+                    (true, _) => 1.0,
+                    (false, true) => fade_in.unwrap_or(1.0),
+                    (false, false) => fade_in.map_or(1.0, |fade_in| 1.0 - fade_in),
+                };
+                sample += voice.samples[voice.position] * voice.duck * voice.fade * envelope;
+                voice.position += 1;
+            }
+            out.push((sample * self.fade).clamp(-1.0, 1.0));
+        }
+
+        if stopping && self.is_stopped() {
+            log_debug!("Stopping audio playback");
+            self.voices.iter_mut().filter(|voice| !voice.overlay).for_each(|voice| {
+                voice.position = voice.samples.len();
+                voice.finish(Ok(()));
+            });
+        }
+        // The player may queue the next clip while the tail of this one crossfades
+        self.voices
+            .iter_mut()
+            .filter(|voice| voice.position + crossfade >= voice.samples.len())
+            .for_each(|voice| voice.finish(Ok(())));
+        self.voices.retain(MixerVoice::is_playing);
+        out
+    }
+}
+
+fn move_towards(value: f32, target: f32, step: f32) -> f32 {
+    if value < target {
+        (value + step).min(target)
+    } else {
+        (value - step).max(target)
+    }
+}
+
+fn output_thread(receiver: Receiver<OutputMessage>) {
+    let mut sink = create_sink(&block_on(AUDIO_CONTROL.read()));
+    log!("Audio output started on {}", sink.name());
+    let mut mixer = Mixer::new();
+    let mut state = TTS_AUDIO_CONTROL.subscribe();
+
+    loop {
+        // Waits for a clip when there is nothing to play, the sink is drained only when the player
+        // does not send the next clip soon, so queued clips play back to back
+        if mixer.is_idle() {
+            let message = match receiver.recv_timeout(IDLE_DRAIN_DELAY) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(e) = sink.drain() {
+                        log_error!("Error draining {}: {}", sink.name(), e);
+                    }
+                    log_debug!("Audio playback finished");
+                    match receiver.recv() {
+                        Ok(message) => message,
+                        Err(_) => break,
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            handle_output_message(message, &mut mixer, &mut sink);
+        }
+        while let Ok(message) = receiver.try_recv() {
+            handle_output_message(message, &mut mixer, &mut sink);
+        }
+
+        let current = *state.borrow_and_update();
+        if mixer.is_paused(current) {
+            // Sleeps until the next transition, resume or skip
+            if block_on(state.changed()).is_err() {
+                break;
+            }
+            continue;
+        }
+
+        let samples = mixer.mix(current, OUTPUT_CHUNK_SAMPLES);
+        if !samples.is_empty()
+            && let Err(e) = write_or_reopen(sink.as_mut(), &samples)
+        {
+            log_error!("Error playing audio on {}: {}", sink.name(), e);
+            mixer.fail(e.to_string());
+            // Drops what is left of the failed clips
+            let _ = sink.flush();
+        }
+    }
+}
+
+fn handle_output_message(message: OutputMessage, mixer: &mut Mixer, sink: &mut Box<dyn AudioSink>) {
+    match message {
+        OutputMessage::Play(request) => mixer.add(request),
+        OutputMessage::Reopen => {
+            sink.close();
+            *sink = create_sink(&block_on(AUDIO_CONTROL.read()));
+            log!("Audio output switched to {}", sink.name());
+        }
+    }
+}
+
+fn db_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+// Gain stage shared by every backend: loudness normalization, then master and source gains
+fn apply_gain(samples: &mut [f32], config: &AudioControl, source: AudioSource) {
+    let mut gain_db = config.gain_db(source);
+    if config.normalize {
+        gain_db += normalization_gain_db(samples, config.normalize_target_db, config.normalize_max_boost_db);
+    }
+    let gain = db_to_amplitude(gain_db);
+    log_trace!("Applying {:.1} dB gain to {:?} clip", gain_db, source);
+    samples
+        .iter_mut()
+        .for_each(|sample| *sample = (*sample * gain).clamp(-1.0, 1.0));
+}
+
+// Gain in dB bringing the RMS level of the clip to target_db, without boosting more than max_boost_db
+// and without pushing the peak above full scale
+fn normalization_gain_db(samples: &[f32], target_db: f32, max_boost_db: f32) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let rms = (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32).sqrt();
+    let peak = samples.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+    if rms <= f32::EPSILON || peak <= f32::EPSILON {
+        return 0.0;
+    }
+    let gain_db = (target_db - 20.0 * rms.log10()).min(max_boost_db);
+    gain_db.min(-20.0 * peak.log10())
+}
+
+// Opens the sink when needed and reopens it once if the device was lost
+fn write_or_reopen(sink: &mut dyn AudioSink, samples: &[f32]) -> Result<()> {
+    if !sink.is_open() {
+        sink.open()?;
+    }
+    if let Err(e) = sink.write(samples) {
+        log_warning!("{} failed: {}, reopening", sink.name(), e);
+        sink.close();
+        sink.open()?;
+        sink.write(samples)?;
+    }
+    Ok(())
+}
+
+// Queues the clip after the clips of the same or higher priority, and applies its preemption rule
+// to the playing clip when that one has a lower priority, a ducking clip is not queued but mixed right away
+pub async fn enqueue(mut clip: AudioClip) {
+    let rule = AUDIO_CONTROL.read().await.queue_rule(clip.source);
+    clip.priority = rule.priority;
+
+    if let Some(playing) = TTS_AUDIO_CONTROL.playing_priority().await
+        && playing < rule.priority
+    {
+        match rule.preemption {
+            Preemption::Wait => {}
+            Preemption::Duck => {
+                log_debug!("Playing a {:?} clip over the playing clip", clip.source);
+                tokio::spawn(async move {
+                    if let Err(e) = AUDIO_OUTPUT.play_over(clip).await {
+                        log_error!("Error playing audio: {}", e);
+                    }
+                });
+                return;
+            }
+            Preemption::Interrupt => {
+                log_debug!("Interrupting the playing clip for a {:?} clip", clip.source);
+                TTS_AUDIO_CONTROL.skip();
+            }
+        }
+    }
+
+    let priority = clip.priority;
+    TTS_AUDIO_QUEUE
+        .insert_before(clip, |queued| queued.priority < priority)
+        .await;
+}
+
 pub async fn start() -> Result<()> {
     // Warm up the AUDIO_CONTROL
-    AUDIO_CONTROL.warm_up();
+    AUDIO_CONTROL.read().await.warm_up();
     BOT_COMMANDS
-        .add_command(
+        .register(
             "stop",
-            Arc::new(|irc_message| Box::pin(bot_cmd_stop_audio(irc_message))),
+            CommandSpec {
+                permission: Role::Moderator.into(),
+                description: "Stops the playing clip".into(),
+                category: "audio".into(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_stop_audio(irc_message, args))),
         )
         .await;
 
-    while let Some(audio) = TTS_AUDIO_QUEUE.next().await {
-        #[cfg(target_os = "linux")]
-        if let Some(sink_name) = &AUDIO_CONTROL.linux_sink_name {
-            match tokio::spawn(play_on_sink(audio.clone(), sink_name)).await {
-                Ok(Ok(())) => {
-                    log_debug!("Audio played on sink: {}", sink_name);
-                }
-                Ok(Err(e)) => {
-                    log_error!("Error playing audio on sink: {}", e);
-                }
-                Err(e) => {
-                    log_error!("Error playing audio on sink: {}", e);
-                }
-            }
-        } else {
-            tokio::spawn(play_on_kira(audio.clone())).await??;
+    // Registering the moderator transport commands
+    for (trigger, description) in [
+        ("pause", "Pauses the audio output"),
+        ("resume", "Resumes the paused audio output"),
+        ("skip", "Skips the playing clip"),
+        ("clear", "Empties the audio queue"),
+        ("replay", "Plays the last clip again"),
+    ] {
+        BOT_COMMANDS
+            .register(
+                trigger,
+                CommandSpec {
+                    permission: Role::Moderator.into(),
+                    description: description.into(),
+                    category: "audio".into(),
+                    ..Default::default()
+                },
+                Arc::new(move |irc_message, args| Box::pin(bot_cmd_transport(irc_message, args, trigger))),
+            )
+            .await;
+    }
+
+    // Everyone can see the settings, the subcommands changing them are for the moderators
+    let mut volume_subcommands = ["master", "speech", "effects"]
+        .map(|gain| Subcommand {
+            name: gain,
+            permission: Role::Moderator.into(),
+            args: vec![ArgSpec::required("dB", VOLUME_DB)],
+        })
+        .to_vec();
+    volume_subcommands.push(Subcommand {
+        name: "normalize",
+        permission: Role::Moderator.into(),
+        args: vec![ArgSpec::required("state", ArgKind::Choice(&["on", "off"]))],
+    });
+    BOT_COMMANDS
+        .register(
+            "volume",
+            CommandSpec {
+                description: "Shows the volumes, moderators can change them".into(),
+                category: "audio".into(),
+                subcommands: volume_subcommands,
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_volume(irc_message, args))),
+        )
+        .await;
+
+    BOT_COMMANDS
+        .register(
+            "audio",
+            CommandSpec {
+                description: "Shows the audio output, moderators can switch the device".into(),
+                category: "audio".into(),
+                subcommands: vec![
+                    Subcommand {
+                        name: "devices",
+                        ..Default::default()
+                    },
+                    Subcommand {
+                        name: "device",
+                        permission: Role::Moderator.into(),
+                        // Device names often contain spaces, "default" is the default device
+                        args: vec![ArgSpec::required("name", ArgKind::Rest)],
+                    },
+                ],
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_audio(irc_message, args))),
+        )
+        .await;
+
+    BOT_COMMANDS.builtins_registered("audio_player").await;
+
+    while let Some(clip) = TTS_AUDIO_QUEUE.next().await {
+        TTS_AUDIO_CONTROL.begin_clip(&clip).await;
+        if let Err(e) = AUDIO_OUTPUT.play(clip).await {
+            log_error!("Error playing audio: {}", e);
         }
-        #[cfg(not(target_os = "linux"))]
-        tokio::spawn(play_on_kira(audio)).await??;
+        TTS_AUDIO_CONTROL.end_clip();
     }
 
     Ok(())
 }
 
-#[cfg(target_os = "linux")]
-pub async fn play_on_sink(audio: Vec<u8>, sink_name: impl AsRef<str>) -> Result<()> {
-    let cursor = Cursor::new(audio);
-    let source = Decoder::new(cursor)?.convert_samples::<f32>();
-    let sample_rate = source.sample_rate();
-    let channels = source.channels() as u8;
+pub async fn bot_cmd_stop_audio(_message: IrcMessage, _args: CommandArgs) -> Result<()> {
+    if !TTS_AUDIO_CONTROL.skip() {
+        log_debug!("Audio is not playing");
+    }
+    Ok(())
+}
 
-    log_trace!("Sample rate: {}, channels {}.", sample_rate, channels);
+pub async fn bot_cmd_transport(message: IrcMessage, _args: CommandArgs, command: &str) -> Result<()> {
+    let reply = match command {
+        "pause" if TTS_AUDIO_CONTROL.pause() => "Audio paused".to_string(),
+        "resume" if TTS_AUDIO_CONTROL.resume() => "Audio resumed".to_string(),
+        "skip" if TTS_AUDIO_CONTROL.skip() => "Skipped the current clip".to_string(),
+        "clear" => format!("Cleared {} queued clips", TTS_AUDIO_CONTROL.clear().await),
+        "replay" if TTS_AUDIO_CONTROL.replay().await => "Replaying the last clip".to_string(),
+        "replay" => "Nothing to replay".to_string(),
+        _ => format!("Audio player is {:?}", TTS_AUDIO_CONTROL.state()).to_lowercase(),
+    };
+    TWITCH_RECEIVER
+        .send_privmsg(format!("@{}, {}", message.sender, reply))
+        .await;
+    Ok(())
+}
 
-    // let (_stream, stream_handle) = OutputStream::try_default().unwrap();
+// Range of the master, speech and effects volumes
+const VOLUME_DB: ArgKind = ArgKind::Number { min: -60.0, max: 20.0 };
 
-    let spec = Spec {
-        format: Format::FLOAT32NE,
-        channels,
-        rate: sample_rate,
-    };
-    assert!(spec.is_valid());
-
-    let sink = Simple::new(
-        None,                     // Use the default server
-        "botox",                  // Our application’s name
-        Direction::Playback,      // We want a playback stream
-        Some(sink_name.as_ref()), // Use the default device if failed
-        "botox tts",              // Description of our stream
-        &spec,                    // Our sample format
-        None,                     // Use default channel map
-        None,                     // Use default buffering attributes
-    )
-    .unwrap();
-
-    let audio_data = source.into_iter().collect::<Vec<_>>();
-    let audio = audio_data
-        .iter()
-        .flat_map(|&x| x.to_le_bytes().to_vec())
-        .collect::<Vec<_>>();
-
-    TTS_AUDIO_CONTROL.set_status_busy().await;
-    log_debug!("Setting audio player to busy");
-    let audio_chunks = audio.chunks(1024);
-    TTS_AUDIO_CONTROL.set_status_busy().await;
-    for chunk in audio_chunks {
-        match TTS_AUDIO_CONTROL.get_status().await {
-            PlayerCommands::Busy => {
-                sink.write(chunk)?;
-            }
-            PlayerCommands::Stop => {
-                log_debug!("Stopping audio playback");
-                TTS_AUDIO_CONTROL.set_status_ready().await;
-                log_debug!("Setting audio player ready");
-                break;
-            }
-            _ => {}
+// !volume [master|speech|effects <dB>] [normalize on|off]
+pub async fn bot_cmd_volume(message: IrcMessage, args: CommandArgs) -> Result<()> {
+    let mut config = AUDIO_CONTROL.write().await;
+    if let Some(subcommand) = args.subcommand() {
+        let db = args.number("dB").unwrap_or_default() as f32;
+        match subcommand {
+            "master" => config.volume = db,
+            "speech" => config.speech_volume = db,
+            "effects" => config.effects_volume = db,
+            _ => config.normalize = args.text("state") == Some("on"),
         }
-        // if TTS_AUDIO_CONTROL.get_status().await != PlayerCommands::Stop {
-        //     sink.write(chunk)?;
-        // } else {
-        //     log_debug!("Stopping audio playback");
-        //     TTS_AUDIO_CONTROL.set_status_ready().await;
-        //     log_debug!("Setting audio player ready");
-        //     break;
-        // }
+        config.save(CONFIG_DIR).await;
     }
-    log_debug!("Audio playback finished");
-    TTS_AUDIO_CONTROL.set_status_ready().await;
 
+    TWITCH_RECEIVER
+        .send_privmsg(format!(
+            "@{}, volume: master {:+.1} dB, speech {:+.1} dB, effects {:+.1} dB, normalize {}",
+            message.sender,
+            config.volume,
+            config.speech_volume,
+            config.effects_volume,
+            if config.normalize { "on" } else { "off" }
+        ))
+        .await;
     Ok(())
 }
 
-pub async fn bot_cmd_stop_audio(_message: IrcMessage) -> Result<()> {
-    TTS_AUDIO_CONTROL.set_status_stop().await;
+// !audio [devices] [device <name>|default]
+pub async fn bot_cmd_audio(message: IrcMessage, args: CommandArgs) -> Result<()> {
+    let (backend, device) = {
+        let config = AUDIO_CONTROL.read().await;
+        (config.backend(), config.device_name())
+    };
+    let backend_name = format!("{:?}", backend).to_lowercase();
+
+    let reply = match args.subcommand() {
+        None => format!(
+            "Audio output: {} on {}",
+            backend_name,
+            device.as_deref().unwrap_or("the default device")
+        ),
+        Some("devices") => match tokio::task::spawn_blocking(move || list_devices(backend)).await? {
+            Ok(devices) if devices.is_empty() => format!("The {} backend has no output devices", backend_name),
+            Ok(devices) => format!("Output devices: {}", devices.join(", ")),
+            Err(e) => format!("Unable to list the {} output devices: {}", backend_name, e),
+        },
+        Some(_) => {
+            let name = args.text("name").unwrap_or_default().to_string();
+            let device = if name == "default" {
+                None
+            } else {
+                let devices = tokio::task::spawn_blocking(move || list_devices(backend))
+                    .await?
+                    .unwrap_or_default();
+                match devices.into_iter().find(|device| device.eq_ignore_ascii_case(&name)) {
+                    Some(device) => Some(device),
+                    None => {
+                        TWITCH_RECEIVER
+                            .send_privmsg(format!(
+                                "@{}, output device {} not found, see {}audio devices",
+                                message.sender,
+                                name,
+                                BOT_COMMANDS_CONFIG.prefix(&message.destination)
+                            ))
+                            .await;
+                        return Ok(());
+                    }
+                }
+            };
+
+            let mut config = AUDIO_CONTROL.write().await;
+            config.set_device_name(device.clone());
+            config.save(CONFIG_DIR).await;
+            drop(config);
+            AUDIO_OUTPUT.reopen()?;
+            format!(
+                "Audio output switched to {}",
+                device.as_deref().unwrap_or("the default device")
+            )
+        }
+    };
+    TWITCH_RECEIVER
+        .send_privmsg(format!("@{}, {}", message.sender, reply))
+        .await;
     Ok(())
 }
 
-pub async fn play_on_kira(audio: Vec<u8>) -> Result<()> {
-    let mut manager = AudioManager::<DefaultBackend>::new(AudioManagerSettings::default())?;
-    let sound_data = StaticSoundData::from_cursor(Cursor::new(audio))?.volume(AUDIO_CONTROL.volume);
-    let sound = manager.play(sound_data.clone())?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    TTS_AUDIO_CONTROL.set_status_busy().await;
-    while TTS_AUDIO_CONTROL.get_status().await != PlayerCommands::Stop
-        && sound.state() == kira::sound::PlaybackState::Playing
-    {
-        sleep(Duration::from_millis(100));
+    const SETTINGS: MixSettings = MixSettings {
+        fade_samples: 4,
+        crossfade_samples: 10,
+        duck_gain: 0.5,
+    };
+
+    fn request(
+        samples: Vec<f32>,
+        priority: u8,
+        overlay: bool,
+    ) -> (OutputRequest, tokio::sync::oneshot::Receiver<Result<()>>) {
+        let (done, result) = tokio::sync::oneshot::channel();
+        let request = OutputRequest {
+            samples,
+            priority,
+            overlay,
+            settings: SETTINGS,
+            done,
+        };
+        (request, result)
+    }
+
+    #[test]
+    fn skip_keeps_the_overlays_playing() {
+        let mut mixer = Mixer::new();
+        let (speech, mut speech_done) = request(vec![0.5; 100], 1, false);
+        let (alert, mut alert_done) = request(vec![0.25; 100], 3, true);
+        mixer.add(speech);
+        mixer.add(alert);
+        mixer.mix(PlayerState::Playing, 20);
+
+        mixer.mix(PlayerState::Stopping, 20);
+        assert!(speech_done.try_recv().unwrap().is_ok());
+        assert!(alert_done.try_recv().is_err());
+        assert_eq!(mixer.voices.len(), 1);
+        assert!(mixer.voices[0].overlay);
+    }
+
+    #[test]
+    fn first_clip_after_silence_does_not_fade_in() {
+        let mut mixer = Mixer::new();
+        let (clip, _done) = request(vec![0.5; 100], 1, false);
+        mixer.add(clip);
+        assert!(!mixer.voices[0].fade_in);
+        assert_eq!(mixer.mix(PlayerState::Playing, 1), vec![0.5]);
+    }
+
+    #[test]
+    fn next_clip_crossfades_with_the_playing_one() {
+        let mut mixer = Mixer::new();
+        let (first, mut first_done) = request(vec![0.5; 100], 1, false);
+        mixer.add(first);
+        mixer.mix(PlayerState::Playing, 95);
+        // The player is told to send the next clip once the tail is within the crossfade
+        assert!(first_done.try_recv().unwrap().is_ok());
+
+        let (next, _done) = request(vec![0.5; 100], 1, false);
+        mixer.add(next);
+        assert!(mixer.voices[1].fade_in);
+        // The first clip is at full level and the next one silent when the crossfade starts
+        assert_eq!(mixer.mix(PlayerState::Playing, 1), vec![0.5]);
+    }
+
+    #[test]
+    fn normalization_reaches_the_target_level() {
+        // RMS of -20 dB brought to -18 dB
+        let gain = normalization_gain_db(&[0.1; 100], -18.0, 12.0);
+        assert!((gain - 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn normalization_limits_the_boost() {
+        // -40 dB would need 22 dB
+        assert_eq!(normalization_gain_db(&[0.01; 100], -18.0, 12.0), 12.0);
+        // A loud peak keeps the clip from clipping
+        let mut samples = vec![0.01; 100];
+        samples[0] = 0.5;
+        assert!(normalization_gain_db(&samples, -18.0, 12.0) <= -20.0 * 0.5f32.log10());
+    }
+
+    #[test]
+    fn normalization_leaves_silence_alone() {
+        assert_eq!(normalization_gain_db(&[0.0; 100], -18.0, 12.0), 0.0);
+        assert_eq!(normalization_gain_db(&[], -18.0, 12.0), 0.0);
     }
-    TTS_AUDIO_CONTROL.set_status_ready().await;
-    Ok(())
 }
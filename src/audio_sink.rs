@@ -0,0 +1,739 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use eyre::{Result, eyre};
+use kira::info::Info;
+use kira::sound::{Sound, SoundData};
+use kira::{AudioManager, AudioManagerSettings, DefaultBackend, Frame};
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+// compile this only for linux
+#[cfg(target_os = "linux")]
+use psimple::Simple;
+// compile this only for linux
+#[cfg(target_os = "linux")]
+use pulse::sample::{Format, Spec};
+// compile this only for linux
+#[cfg(target_os = "linux")]
+use pulse::stream::Direction;
+// compile this only for linux
+#[cfg(target_os = "linux")]
+use pulse::{
+    callbacks::ListResult,
+    context::{Context, FlagSet, State},
+    mainloop::standard::{IterateResult, Mainloop},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::audio_player::AudioControl;
+
+// Sample format every sink is fed with, clips are converted to it before playback
+pub static OUTPUT_SAMPLE_RATE: u32 = 48000;
+pub static OUTPUT_CHANNELS: u16 = 2;
+
+// How much audio the kira stream buffers ahead of the device, stop and pause are heard this late
+static KIRA_BUFFER_SECS: f32 = 0.1;
+// A kira stream not pulling samples for this long is considered lost and reopened
+static KIRA_STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+const WAV_HEADER_LEN: u32 = 44;
+// The RIFF size field, the data length plus the rest of the header, is a u32
+const WAV_MAX_DATA_LEN: u32 = u32::MAX - (WAV_HEADER_LEN - 8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioBackend {
+    Kira,
+    Pulse,
+    Wav,
+    Null,
+}
+
+// An audio output fed with interleaved f32 samples in OUTPUT_SAMPLE_RATE / OUTPUT_CHANNELS format.
+// Sinks live on the audio output thread, open lazily and can be reopened after a failure.
+pub trait AudioSink {
+    fn name(&self) -> String;
+
+    fn is_open(&self) -> bool;
+
+    fn open(&mut self) -> Result<()>;
+
+    fn close(&mut self);
+
+    // Blocks until the samples are accepted by the output
+    fn write(&mut self, samples: &[f32]) -> Result<()>;
+
+    // Blocks until every written sample has been played
+    fn drain(&mut self) -> Result<()>;
+
+    // Drops written samples that have not been played yet
+    fn flush(&mut self) -> Result<()>;
+}
+
+pub fn create_sink(config: &AudioControl) -> Box<dyn AudioSink> {
+    let backend = config.backend();
+    let device = validate_device(backend, config.device_name());
+    match backend {
+        AudioBackend::Kira => Box::new(KiraSink::new(device)),
+        #[cfg(target_os = "linux")]
+        AudioBackend::Pulse => Box::new(PulseSink::new(device)),
+        #[cfg(not(target_os = "linux"))]
+        AudioBackend::Pulse => {
+            log_warning!("PulseAudio is only available on Linux, using kira");
+            Box::new(KiraSink::default())
+        }
+        AudioBackend::Wav => Box::new(WavFileSink::new(config.wav_output_path())),
+        AudioBackend::Null => Box::new(NullSink::default()),
+    }
+}
+
+// Output devices of the backend, by the name used in AudioControl.toml. Files and null have none.
+pub fn list_devices(backend: AudioBackend) -> Result<Vec<String>> {
+    match backend {
+        AudioBackend::Kira => Ok(rodio::cpal::default_host()
+            .output_devices()?
+            .filter_map(|device| device.name().ok())
+            .collect()),
+        #[cfg(target_os = "linux")]
+        AudioBackend::Pulse => pulse_sink_names(),
+        #[cfg(not(target_os = "linux"))]
+        AudioBackend::Pulse => Err(eyre!("PulseAudio is only available on Linux")),
+        AudioBackend::Wav | AudioBackend::Null => Ok(Vec::new()),
+    }
+}
+
+// Logs the available devices and falls back to the default device when the configured one is missing
+fn validate_device(backend: AudioBackend, device: Option<String>) -> Option<String> {
+    let devices = match list_devices(backend) {
+        Ok(devices) => devices,
+        Err(e) => {
+            log_warning!("Unable to list the {:?} output devices: {}", backend, e);
+            return device;
+        }
+    };
+    if !devices.is_empty() {
+        log!("Available {:?} output devices: {}", backend, devices.join(", "));
+    }
+    match device {
+        Some(name) if !devices.contains(&name) => {
+            log_warning!("Output device {} not found, using the default device", name);
+            None
+        }
+        device => device,
+    }
+}
+
+// Asks the PulseAudio server for its sinks, blocking until the answer
+#[cfg(target_os = "linux")]
+fn pulse_sink_names() -> Result<Vec<String>> {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    let mut mainloop = Mainloop::new().ok_or_else(|| eyre!("Unable to create the PulseAudio mainloop"))?;
+    let mut context =
+        Context::new(&mainloop, "botox").ok_or_else(|| eyre!("Unable to create the PulseAudio context"))?;
+    context.connect(None, FlagSet::NOFLAGS, None)?;
+    loop {
+        if let IterateResult::Quit(_) | IterateResult::Err(_) = mainloop.iterate(true) {
+            return Err(eyre!("PulseAudio mainloop stopped"));
+        }
+        match context.get_state() {
+            State::Ready => break,
+            State::Failed | State::Terminated => return Err(eyre!("Unable to connect to PulseAudio")),
+            _ => {}
+        }
+    }
+
+    let names = Rc::new(RefCell::new(Vec::new()));
+    let done = Rc::new(Cell::new(false));
+    let _operation = context.introspect().get_sink_info_list({
+        let names = names.clone();
+        let done = done.clone();
+        move |result| match result {
+            ListResult::Item(info) => {
+                if let Some(name) = &info.name {
+                    names.borrow_mut().push(name.to_string());
+                }
+            }
+            ListResult::End | ListResult::Error => done.set(true),
+        }
+    });
+    while !done.get() {
+        if let IterateResult::Quit(_) | IterateResult::Err(_) = mainloop.iterate(true) {
+            break;
+        }
+    }
+    context.disconnect();
+    Ok(names.take())
+}
+
+fn samples_duration(samples: usize) -> Duration {
+    Duration::from_secs_f64(samples as f64 / (OUTPUT_SAMPLE_RATE as f64 * OUTPUT_CHANNELS as f64))
+}
+
+// Samples shared between the output thread and the kira audio thread, which signals every pull
+#[derive(Default)]
+struct StreamBuffer {
+    samples: Mutex<VecDeque<f32>>,
+    pulled: Condvar,
+}
+
+impl StreamBuffer {
+    fn lock(&self) -> Result<MutexGuard<'_, VecDeque<f32>>> {
+        self.samples.lock().map_err(|_| eyre!("kira stream buffer is poisoned"))
+    }
+
+    // Blocks until at most max samples are buffered, without polling
+    fn wait_until_below(&self, max: usize) -> Result<()> {
+        let mut samples = self.lock()?;
+        while samples.len() > max {
+            let buffered = samples.len();
+            let (guard, timeout) = self
+                .pulled
+                .wait_timeout(samples, KIRA_STALL_TIMEOUT)
+                .map_err(|_| eyre!("kira stream buffer is poisoned"))?;
+            samples = guard;
+            if timeout.timed_out() && samples.len() >= buffered {
+                return Err(eyre!("kira stream stalled"));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Kira pulls frames from a shared buffer through a never ending streaming sound
+pub struct KiraSink {
+    // cpal device name, the default device when None
+    device: Option<String>,
+    manager: Option<AudioManager<DefaultBackend>>,
+    buffer: Arc<StreamBuffer>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Default for KiraSink {
+    fn default() -> Self {
+        Self {
+            device: None,
+            manager: None,
+            buffer: Arc::new(StreamBuffer::default()),
+            closed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl KiraSink {
+    pub fn new(device: Option<String>) -> Self {
+        Self {
+            device,
+            ..Default::default()
+        }
+    }
+}
+
+impl AudioSink for KiraSink {
+    fn name(&self) -> String {
+        match &self.device {
+            Some(device) => format!("kira on {}", device),
+            None => "kira".into(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.manager.is_some()
+    }
+
+    fn open(&mut self) -> Result<()> {
+        log!("Opening {} audio output", self.name());
+        let mut settings = AudioManagerSettings::<DefaultBackend>::default();
+        if let Some(name) = &self.device {
+            settings.backend.device = rodio::cpal::default_host()
+                .output_devices()?
+                .find(|device| device.name().is_ok_and(|device_name| device_name == *name));
+            if settings.backend.device.is_none() {
+                return Err(eyre!("Output device {} not found", name));
+            }
+        }
+        let mut manager = AudioManager::<DefaultBackend>::new(settings)?;
+
+        self.closed = Arc::new(AtomicBool::new(false));
+        manager
+            .play(StreamSoundData {
+                buffer: self.buffer.clone(),
+                closed: self.closed.clone(),
+            })
+            .map_err(|e| eyre!("Unable to start kira stream: {:?}", e))?;
+        self.manager = Some(manager);
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.manager = None;
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        let max_buffered = (KIRA_BUFFER_SECS * (OUTPUT_SAMPLE_RATE * OUTPUT_CHANNELS as u32) as f32) as usize;
+        self.buffer.wait_until_below(max_buffered)?;
+        self.buffer.lock()?.extend(samples);
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        self.buffer.wait_until_below(0)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.buffer.lock()?.clear();
+        self.buffer.pulled.notify_all();
+        Ok(())
+    }
+}
+
+struct StreamSoundData {
+    buffer: Arc<StreamBuffer>,
+    closed: Arc<AtomicBool>,
+}
+
+impl SoundData for StreamSoundData {
+    type Error = ();
+    type Handle = ();
+
+    fn into_sound(self) -> Result<(Box<dyn Sound>, Self::Handle), Self::Error> {
+        Ok((
+            Box::new(StreamSound {
+                buffer: self.buffer,
+                closed: self.closed,
+                phase: 0.0,
+                current: Frame::ZERO,
+                next: Frame::ZERO,
+            }),
+            (),
+        ))
+    }
+}
+
+struct StreamSound {
+    buffer: Arc<StreamBuffer>,
+    closed: Arc<AtomicBool>,
+    phase: f64,
+    current: Frame,
+    next: Frame,
+}
+
+impl Sound for StreamSound {
+    fn process(&mut self, out: &mut [Frame], dt: f64, _info: &Info) {
+        // Linear resampling from OUTPUT_SAMPLE_RATE to the device rate (1 / dt)
+        let step = dt * OUTPUT_SAMPLE_RATE as f64;
+        let Ok(mut buffer) = self.buffer.samples.lock() else {
+            out.fill(Frame::ZERO);
+            return;
+        };
+        let buffered = buffer.len();
+
+        for frame in out.iter_mut() {
+            self.phase += step;
+            while self.phase >= 1.0 {
+                self.phase -= 1.0;
+                self.current = self.next;
+                self.next = match (buffer.pop_front(), buffer.pop_front()) {
+                    (Some(left), Some(right)) => Frame { left, right },
+                    _ => Frame::ZERO,
+                };
+            }
+            let t = self.phase as f32;
+            *frame = Frame {
+                left: self.current.left + (self.next.left - self.current.left) * t,
+                right: self.current.right + (self.next.right - self.current.right) * t,
+            };
+        }
+        let pulled = buffered != buffer.len();
+        drop(buffer);
+        // Wakes the output thread waiting for room in the buffer or for the drain
+        if pulled {
+            self.buffer.pulled.notify_all();
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub struct PulseSink {
+    sink_name: Option<String>,
+    stream: Option<Simple>,
+}
+
+#[cfg(target_os = "linux")]
+impl PulseSink {
+    pub fn new(sink_name: Option<String>) -> Self {
+        Self {
+            sink_name,
+            stream: None,
+        }
+    }
+
+    fn stream(&mut self) -> Result<&mut Simple> {
+        if self.stream.is_none() {
+            self.open()?;
+        }
+        self.stream.as_mut().ok_or_else(|| eyre!("PulseAudio sink is not open"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl AudioSink for PulseSink {
+    fn name(&self) -> String {
+        format!("PulseAudio sink {}", self.sink_name.as_deref().unwrap_or("default"))
+    }
+
+    fn is_open(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn open(&mut self) -> Result<()> {
+        let spec = Spec {
+            format: Format::FLOAT32NE,
+            channels: OUTPUT_CHANNELS as u8,
+            rate: OUTPUT_SAMPLE_RATE,
+        };
+        assert!(spec.is_valid());
+
+        log!("Opening {}", self.name());
+        let stream = Simple::new(
+            None,                      // Use the default server
+            "botox",                   // Our application’s name
+            Direction::Playback,       // We want a playback stream
+            self.sink_name.as_deref(), // Use the default device if None
+            "botox tts",               // Description of our stream
+            &spec,                     // Our sample format
+            None,                      // Use default channel map
+            None,                      // Use default buffering attributes
+        )
+        .map_err(|e| eyre!("Unable to open {}: {}", self.name(), e))?;
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.stream = None;
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        let bytes = samples
+            .iter()
+            .flat_map(|sample| sample.to_ne_bytes())
+            .collect::<Vec<_>>();
+        let name = self.name();
+        self.stream()?
+            .write(&bytes)
+            .map_err(|e| eyre!("Unable to write to {}: {}", name, e))
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        let name = self.name();
+        self.stream()?
+            .drain()
+            .map_err(|e| eyre!("Unable to drain {}: {}", name, e))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let name = self.name();
+        self.stream()?
+            .flush()
+            .map_err(|e| eyre!("Unable to flush {}: {}", name, e))
+    }
+}
+
+// Renders everything played into a 32 bit float WAV file, for headless servers and tests.
+// An existing file is appended to, a full one or one of another format is renamed and a new file is started.
+pub struct WavFileSink {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    data_len: u32,
+}
+
+impl WavFileSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            writer: None,
+            data_len: 0,
+        }
+    }
+
+    fn write_header(writer: &mut BufWriter<File>, data_len: u32) -> Result<()> {
+        let block_align = OUTPUT_CHANNELS * 4;
+        writer.seek(SeekFrom::Start(0))?;
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(WAV_HEADER_LEN - 8 + data_len).to_le_bytes())?;
+        writer.write_all(b"WAVEfmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&3u16.to_le_bytes())?; // IEEE float
+        writer.write_all(&OUTPUT_CHANNELS.to_le_bytes())?;
+        writer.write_all(&OUTPUT_SAMPLE_RATE.to_le_bytes())?;
+        writer.write_all(&(OUTPUT_SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&32u16.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&data_len.to_le_bytes())?;
+        writer.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    // Audio length of a file written by this sink, None when it is missing or has another format
+    fn existing_data_len(file: &mut File) -> Option<u32> {
+        let mut header = [0u8; WAV_HEADER_LEN as usize];
+        file.read_exact(&mut header).ok()?;
+        // IEEE float, channels and sample rate
+        let mut expected_format = 3u16.to_le_bytes().to_vec();
+        expected_format.extend_from_slice(&OUTPUT_CHANNELS.to_le_bytes());
+        expected_format.extend_from_slice(&OUTPUT_SAMPLE_RATE.to_le_bytes());
+        if header[0..4] != *b"RIFF" || header[8..16] != *b"WAVEfmt " || header[20..28] != expected_format[..] {
+            return None;
+        }
+        // The file may be longer than its header says, when the bot stopped before the header was updated
+        let data_len = file.metadata().ok()?.len().checked_sub(WAV_HEADER_LEN as u64)?;
+        let frame_len = OUTPUT_CHANNELS as u64 * 4;
+        u32::try_from(data_len - data_len % frame_len)
+            .ok()
+            .filter(|data_len| *data_len <= WAV_MAX_DATA_LEN)
+    }
+
+    fn now_secs() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    // Keeps the full file under another name, e.g. audio_output-1700000000.wav
+    fn roll_over(&mut self) -> Result<()> {
+        self.close();
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let full = self.path.with_file_name(format!("{}-{}.wav", stem, Self::now_secs()));
+        log!("{} is full, keeping it as {}", self.name(), full.display());
+        std::fs::rename(&self.path, &full)?;
+        self.open()
+    }
+}
+
+impl AudioSink for WavFileSink {
+    fn name(&self) -> String {
+        format!("WAV file {}", self.path.display())
+    }
+
+    fn is_open(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    fn open(&mut self) -> Result<()> {
+        log!("Opening {}", self.name());
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+        let data_len = match Self::existing_data_len(&mut file) {
+            Some(data_len) => data_len,
+            None if file.metadata()?.len() == 0 => 0,
+            // A file the sink didn't write is kept, e.g. as audio_output.wav.1700000000.bak
+            None => {
+                drop(file);
+                let file_name = self.path.file_name().unwrap_or_default().to_string_lossy();
+                let aside = self
+                    .path
+                    .with_file_name(format!("{}.{}.bak", file_name, Self::now_secs()));
+                log_warning!("{} has another format, moving it to {}", self.name(), aside.display());
+                std::fs::rename(&self.path, &aside)?;
+                return self.open();
+            }
+        };
+        file.set_len(WAV_HEADER_LEN as u64 + data_len as u64)?;
+        let mut writer = BufWriter::new(file);
+        Self::write_header(&mut writer, data_len)?;
+        self.writer = Some(writer);
+        self.data_len = data_len;
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        let _ = self.drain();
+        self.writer = None;
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        if self.writer.is_none() {
+            self.open()?;
+        }
+        let len = u32::try_from(samples.len() * 4)
+            .ok()
+            .filter(|len| *len <= WAV_MAX_DATA_LEN)
+            .ok_or_else(|| eyre!("{} samples don't fit in a WAV file", samples.len()))?;
+        if self
+            .data_len
+            .checked_add(len)
+            .is_none_or(|data_len| data_len > WAV_MAX_DATA_LEN)
+        {
+            self.roll_over()?;
+        }
+        let writer = self.writer.as_mut().ok_or_else(|| eyre!("WAV file is not open"))?;
+        for sample in samples {
+            writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len += len;
+        Ok(())
+    }
+
+    // Keeps the header sizes up to date, so the file is valid after every clip
+    fn drain(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            Self::write_header(writer, self.data_len)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for WavFileSink {
+    fn drop(&mut self) {
+        let _ = self.drain();
+    }
+}
+
+// Discards the audio, paced in real time like a device would be
+#[derive(Default)]
+pub struct NullSink {
+    open: bool,
+    // When the written samples are done playing
+    played_until: Option<Instant>,
+}
+
+impl AudioSink for NullSink {
+    fn name(&self) -> String {
+        "null".into()
+    }
+
+    fn is_open(&self) -> bool {
+        self.open
+    }
+
+    fn open(&mut self) -> Result<()> {
+        self.open = true;
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.open = false;
+    }
+
+    // Sleeps once up to the deadline of the previous write, so the pace does not drift
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        let now = Instant::now();
+        let start = self.played_until.filter(|until| *until > now).unwrap_or(now);
+        sleep(start - now);
+        self.played_until = Some(start + samples_duration(samples.len()));
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        if let Some(until) = self.played_until.take() {
+            sleep(until.saturating_duration_since(Instant::now()));
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.played_until = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_wav(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("bottarga-{}-{}.wav", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn header_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn wav_header_matches_the_rendered_samples() {
+        let path = temp_wav("header");
+        let samples = [0.25f32; 4800];
+        let mut sink = WavFileSink::new(&path);
+        sink.write(&samples).unwrap();
+        sink.drain().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + samples.len() * 4);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(header_u32(&bytes, 4), 36 + samples.len() as u32 * 4);
+        assert_eq!(header_u32(&bytes, 24), OUTPUT_SAMPLE_RATE);
+        assert_eq!(header_u32(&bytes, 40), samples.len() as u32 * 4);
+        assert_eq!(f32::from_le_bytes(bytes[44..48].try_into().unwrap()), 0.25);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn wav_reopen_appends_to_the_file() {
+        let path = temp_wav("reopen");
+        let mut sink = WavFileSink::new(&path);
+        sink.write(&[0.5; 960]).unwrap();
+        sink.close();
+        let mut sink = WavFileSink::new(&path);
+        sink.write(&[0.5; 960]).unwrap();
+        sink.close();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(header_u32(&bytes, 40), 2 * 960 * 4);
+        assert_eq!(bytes.len(), 44 + 2 * 960 * 4);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn wav_moves_a_file_of_another_format_aside() {
+        let path = temp_wav("other");
+        std::fs::write(&path, b"not a wav file").unwrap();
+        let mut sink = WavFileSink::new(&path);
+        sink.write(&[0.0; 2]).unwrap();
+        sink.close();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(header_u32(&bytes, 40), 8);
+        let _ = std::fs::remove_file(&path);
+
+        let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+        let aside = std::fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .find(|aside| {
+                let name = aside.file_name().unwrap().to_string_lossy();
+                name.starts_with(&format!("{}.", file_name)) && name.ends_with(".bak")
+            })
+            .expect("the old file is kept");
+        assert_eq!(std::fs::read(&aside).unwrap(), b"not a wav file");
+        let _ = std::fs::remove_file(&aside);
+    }
+}
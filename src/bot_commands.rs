@@ -1,60 +1,718 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
 use std::pin::Pin;
 use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 
-use eyre::{Error, Result};
+use eyre::{Error, Result, eyre};
+use futures::executor::block_on;
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
-use crate::bot_external_commands::ExternalBotCommands;
-use crate::irc_parser::IrcMessage;
+use crate::CONFIG_DIR;
+use crate::bot_external_commands::{
+    CUSTOM_COMMAND_USAGE, EXTERNAL_COMMANDS_FILE, ExternalBotCommands, bot_cmd_custom_command, bot_cmd_reload,
+};
+use crate::command_args::{self, ArgKind, ArgSpec, CommandArgs};
+use crate::common::PersistentConfig;
+use crate::irc_parser::{IrcMessage, Role};
 use crate::tts::{TTS_QUEUE, voice_msg};
-use crate::twitch_client::{TWITCH_BOT_INFO, TWITCH_BROADCAST, TWITCH_RECEIVER};
-
-pub static BOT_COMMAND_PREFIX: &str = "!";
+use crate::twitch_client::{
+    TWITCH_BOT_INFO, TWITCH_BROADCAST, TWITCH_DIE, TWITCH_MAX_MSG_LINE_LENGTH, TWITCH_RECEIVER,
+};
+use crate::variables::{VARIABLE_USAGE, VARIABLES, bot_cmd_variable};
 
 pub static BOT_COMMANDS: LazyLock<BotCommands> = LazyLock::new(|| BotCommands::default());
+pub static BOT_COMMANDS_CONFIG: LazyLock<BotCommandsConfig> = LazyLock::new(|| BotCommandsConfig::init(CONFIG_DIR));
+pub static COMMAND_PERMISSIONS: LazyLock<CommandPermissions> = LazyLock::new(|| CommandPermissions::init(CONFIG_DIR));
+pub static COMMAND_COOLDOWNS: LazyLock<CooldownConfig> = LazyLock::new(|| CooldownConfig::init(CONFIG_DIR));
 
 // pub type BotCommandType = fn(IrcMessage) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + Sync>>;
 // pub type BotCommandType = Arc<dyn Fn(IrcMessage) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + Sync>>>;
-pub type BotCommandType =
-    Arc<dyn Fn(IrcMessage) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Sync + Send>> + Sync + Send>;
+pub type BotCommandType = Arc<
+    dyn Fn(IrcMessage, CommandArgs) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Sync + Send>> + Sync + Send,
+>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BotCommandsConfig {
+    // Reads the !help output through TTS on top of the chat reply
+    speak_help: bool,
+    // Prefixes starting a command, e.g. ["!", "?"], the first one is shown in the replies
+    prefixes: Vec<String>,
+    // Channel name -> prefixes, replacing the default ones on that channel
+    channel_prefixes: HashMap<String, Vec<String>>,
+    // Also runs the commands addressed to the bot, e.g. "@bottarga help"
+    mention: bool,
+}
+
+impl Default for BotCommandsConfig {
+    fn default() -> Self {
+        Self {
+            speak_help: true,
+            prefixes: vec!["!".into()],
+            channel_prefixes: HashMap::new(),
+            mention: false,
+        }
+    }
+}
+
+impl PersistentConfig for BotCommandsConfig {}
+
+impl BotCommandsConfig {
+    pub fn init(config_dir: Option<&str>) -> Self {
+        block_on(BotCommandsConfig::load(config_dir))
+    }
+
+    pub fn warm_up(&self) {}
+
+    pub fn prefixes(&self, channel: &str) -> &[String] {
+        self.channel_prefixes
+            .get(channel.trim_start_matches('#'))
+            .filter(|prefixes| !prefixes.is_empty())
+            .unwrap_or(&self.prefixes)
+    }
+
+    // Prefix shown in the replies on the channel
+    pub fn prefix(&self, channel: &str) -> &str {
+        self.prefixes(channel).first().map_or("!", |prefix| prefix.as_str())
+    }
+
+    // Command and arguments of a chat message, e.g. "help 2" for "!help 2" or "@bottarga help 2".
+    // None when the message is not a command.
+    pub async fn command_line<'a>(&self, channel: &str, payload: &'a str) -> Option<&'a str> {
+        // The longest prefix first, so "!!" is not taken for "!"
+        let mut prefixes = self
+            .prefixes(channel)
+            .iter()
+            .filter(|prefix| !prefix.is_empty())
+            .collect::<Vec<_>>();
+        prefixes.sort_by_key(|prefix| std::cmp::Reverse(prefix.len()));
+        if let Some(line) = prefixes.iter().find_map(|prefix| payload.strip_prefix(prefix.as_str())) {
+            return Some(line);
+        }
+
+        // A mention is only a command when a command follows, so chat addressed to the bot is still read
+        if !self.mention {
+            return None;
+        }
+        let (mention, line) = payload.split_once(char::is_whitespace)?;
+        let mention = mention.strip_prefix('@')?.trim_end_matches([':', ',']);
+        if !mention.eq_ignore_ascii_case(&TWITCH_BOT_INFO.nick_name().await) {
+            return None;
+        }
+        let line = line.trim_start();
+        let command = line.split_whitespace().next()?;
+        BOT_COMMANDS.has_command(command).await.then_some(line)
+    }
+
+    // Removes any of the prefixes of the channel, for command names typed with their prefix
+    pub fn trim_prefix<'a>(&self, channel: &str, text: &'a str) -> &'a str {
+        self.prefixes(channel)
+            .iter()
+            .filter(|prefix| !prefix.is_empty())
+            .find_map(|prefix| text.strip_prefix(prefix.as_str()))
+            .unwrap_or(text)
+    }
+}
+
+// Who may run a command: a minimum role, or an explicit list of users.
+// In TOML either `stop = "moderator"` or `stop = { users = ["nick"] }`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Permission {
+    Role(Role),
+    Users { users: Vec<String> },
+}
+
+impl Permission {
+    pub fn allows(&self, message: &IrcMessage) -> bool {
+        match self {
+            Permission::Role(role) => message.role() >= *role,
+            // The broadcaster is never locked out of a command
+            Permission::Users { users } => {
+                message.role() == Role::Broadcaster
+                    || users.iter().any(|user| user.eq_ignore_ascii_case(&message.sender))
+            }
+        }
+    }
+}
+
+impl Default for Permission {
+    fn default() -> Self {
+        Permission::Role(Role::Everyone)
+    }
+}
+
+impl From<Role> for Permission {
+    fn from(role: Role) -> Self {
+        Permission::Role(role)
+    }
+}
+
+impl Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let who = match self {
+            Permission::Role(Role::Everyone) => "everyone",
+            Permission::Role(Role::Subscriber) => "subscribers",
+            Permission::Role(Role::Vip) => "VIPs",
+            Permission::Role(Role::Moderator) => "moderators",
+            Permission::Role(Role::Broadcaster) => "the broadcaster",
+            Permission::Users { .. } => "selected users",
+        };
+        write!(f, "{}", who)
+    }
+}
+
+// Permissions replacing the ones the commands are registered with
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CommandPermissions {
+    // Command trigger -> permission, on every channel
+    overrides: HashMap<String, Permission>,
+    // Channel name -> command trigger -> permission, over the global overrides
+    channels: HashMap<String, HashMap<String, Permission>>,
+}
+
+impl PersistentConfig for CommandPermissions {}
+
+impl CommandPermissions {
+    pub fn init(config_dir: Option<&str>) -> Self {
+        block_on(CommandPermissions::load(config_dir))
+    }
+
+    pub fn warm_up(&self) {}
+
+    pub fn get(&self, channel: &str, trigger: &str) -> Option<&Permission> {
+        self.channels
+            .get(channel.trim_start_matches('#'))
+            .and_then(|commands| commands.get(trigger))
+            .or_else(|| self.overrides.get(trigger))
+    }
+}
+
+// Seconds between two runs of a command, by anyone and by the same user, 0 disables them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Cooldown {
+    pub global_secs: u64,
+    pub user_secs: u64,
+}
+
+impl Cooldown {
+    pub fn is_none(&self) -> bool {
+        self.global_secs == 0 && self.user_secs == 0
+    }
+}
+
+impl Display for Cooldown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.global_secs, self.user_secs) {
+            (0, 0) => write!(f, "none"),
+            (global, 0) => write!(f, "{} s", global),
+            (0, user) => write!(f, "{} s per user", user),
+            (global, user) => write!(f, "{} s, {} s per user", global, user),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CooldownConfig {
+    // Command name -> cooldown, replacing the one the command is registered with
+    overrides: HashMap<String, Cooldown>,
+    // Senders with this role or a higher one are never on cooldown
+    bypass_role: Role,
+    // Tells the sender how long to wait, at most once every reply_interval_secs per sender
+    reply: bool,
+    reply_interval_secs: u64,
+}
+
+impl Default for CooldownConfig {
+    fn default() -> Self {
+        Self {
+            overrides: HashMap::new(),
+            bypass_role: Role::Moderator,
+            reply: true,
+            reply_interval_secs: 30,
+        }
+    }
+}
+
+impl PersistentConfig for CooldownConfig {}
+
+impl CooldownConfig {
+    pub fn init(config_dir: Option<&str>) -> Self {
+        block_on(CooldownConfig::load(config_dir))
+    }
+
+    pub fn warm_up(&self) {}
+}
+
+// Last runs of the commands and last cooldown replies
+#[derive(Default)]
+struct CooldownTracker {
+    global: HashMap<String, Instant>,
+    user: HashMap<(String, String), Instant>,
+    replies: HashMap<String, Instant>,
+}
+
+impl CooldownTracker {
+    // Time left before the sender can run the command again, the run is recorded when there is none
+    fn check(&mut self, name: &str, sender: &str, cooldown: Cooldown) -> Option<Duration> {
+        self.check_at(Instant::now(), name, sender, cooldown)
+    }
+
+    fn check_at(&mut self, now: Instant, name: &str, sender: &str, cooldown: Cooldown) -> Option<Duration> {
+        let user_cooldown = Duration::from_secs(cooldown.user_secs);
+        // The runs of the command past their cooldown are dropped, so the map doesn't grow with every chatter
+        self.user
+            .retain(|(command, _), last| command != name || now - *last < user_cooldown);
+
+        let remaining = |last: Option<&Instant>, secs: u64| {
+            last.map(|last| Duration::from_secs(secs).saturating_sub(now - *last))
+                .filter(|left| !left.is_zero())
+        };
+        let user_key = (name.to_string(), sender.to_string());
+        let left = [
+            remaining(self.global.get(name), cooldown.global_secs),
+            remaining(self.user.get(&user_key), cooldown.user_secs),
+        ]
+        .into_iter()
+        .flatten()
+        .max();
+        if left.is_none() {
+            self.global.insert(name.to_string(), now);
+            if cooldown.user_secs > 0 {
+                self.user.insert(user_key, now);
+            }
+        }
+        left
+    }
+
+    // Whether the sender may be told about the cooldown again
+    fn should_reply(&mut self, sender: &str, interval_secs: u64) -> bool {
+        let now = Instant::now();
+        let interval = Duration::from_secs(interval_secs);
+        self.replies.retain(|_, last| now - *last < interval);
+        if self.replies.contains_key(sender) {
+            return false;
+        }
+        self.replies.insert(sender.to_string(), now);
+        true
+    }
+}
+
+// How a command is registered: who may run it, how often, and how !help describes it
+#[derive(Debug, Clone, Default)]
+pub struct CommandSpec {
+    pub permission: Permission,
+    pub cooldown: Cooldown,
+    pub description: String,
+    // Arguments parsed before the handler runs, a run with invalid ones gets the usage as reply
+    pub args: Vec<ArgSpec>,
+    // Arguments after the trigger, e.g. "<user> [voice]", built from args when empty
+    pub usage: String,
+    // Groups the commands in the !help listing, "general" when empty
+    pub category: String,
+    pub aliases: Vec<String>,
+    // Actions picked by the first argument, with their own permission and arguments
+    pub subcommands: Vec<Subcommand>,
+}
+
+// An action of a command, e.g. "device" in "!audio device <name>". The command permission is checked first.
+#[derive(Debug, Clone, Default)]
+pub struct Subcommand {
+    pub name: &'static str,
+    pub permission: Permission,
+    // Arguments after the subcommand name
+    pub args: Vec<ArgSpec>,
+}
+
+impl Subcommand {
+    pub fn usage(&self) -> String {
+        format!("{} {}", self.name, command_args::usage(&self.args))
+            .trim_end()
+            .to_string()
+    }
+}
+
+impl CommandSpec {
+    pub fn usage(&self) -> String {
+        if !self.usage.is_empty() {
+            return self.usage.clone();
+        }
+        let mut usage = command_args::usage(&self.args);
+        if !self.subcommands.is_empty() {
+            let subcommands = self.subcommands.iter().map(Subcommand::usage).collect::<Vec<_>>();
+            usage = format!("{} [{}]", usage, subcommands.join(" | "))
+                .trim_start()
+                .to_string();
+        }
+        usage
+    }
+
+    // The subcommand named by the first argument of the command line
+    fn subcommand(&self, command_line: &str) -> Option<&Subcommand> {
+        let first = command_line.split_whitespace().nth(1)?;
+        self.subcommands
+            .iter()
+            .find(|subcommand| subcommand.name.eq_ignore_ascii_case(first))
+    }
+
+    fn category(&self) -> &str {
+        if self.category.is_empty() {
+            "general"
+        } else {
+            &self.category
+        }
+    }
+}
+
+#[derive(Clone)]
+struct BotCommand {
+    handler: BotCommandType,
+    spec: CommandSpec,
+    // Registered from ExternalBotCommands.toml, the only commands a reload may replace or remove
+    external: bool,
+}
+
+// Modules registering built-in commands from their own task. The external commands wait for them,
+// so they are checked against every built-in trigger.
+const BUILTIN_MODULES: [&str; 3] = ["tts", "audio_player", "timers"];
+// How long the external commands wait for a module that doesn't start
+const BUILTIN_MODULES_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Default)]
 pub struct BotCommands {
-    commands: RwLock<HashMap<String, BotCommandType>>,
+    commands: RwLock<HashMap<String, BotCommand>>,
+    // Alias -> command name, aliases share the permission and the cooldown of the command
+    aliases: RwLock<HashMap<String, String>>,
+    cooldowns: RwLock<CooldownTracker>,
+    // Modules of BUILTIN_MODULES done registering their commands
+    ready_modules: RwLock<HashSet<&'static str>>,
+    modules_notify: tokio::sync::Notify,
 }
 
 // impl<T: Display> IntoIrcPRIVMSG for T {}
 
 impl BotCommands {
-    pub async fn add_command(&self, trigger: impl Into<String>, command: BotCommandType) {
+    pub async fn register(&self, trigger: impl Into<String>, spec: CommandSpec, command: BotCommandType) {
+        let trigger = trigger.into();
+        if self
+            .commands
+            .read()
+            .await
+            .get(&trigger)
+            .is_some_and(|command| command.external)
+        {
+            log_warning!("Built-in command {} replaces the external one", trigger);
+        }
+        self.insert(trigger, spec, command, false).await;
+    }
+
+    // Registers a command of ExternalBotCommands.toml, false when the trigger or an alias is a built-in command
+    pub async fn register_external(
+        &self,
+        trigger: impl Into<String>,
+        spec: CommandSpec,
+        command: BotCommandType,
+    ) -> bool {
         let trigger = trigger.into();
-        log_debug!("Adding command: {}", trigger);
-        self.commands.write().await.insert(trigger, command);
+        for taken in std::iter::once(&trigger).chain(&spec.aliases) {
+            if self.is_builtin(taken).await {
+                log_error!(
+                    "External command {} not registered, {} is a built-in command",
+                    trigger,
+                    taken
+                );
+                return false;
+            }
+        }
+        self.insert(trigger, spec, command, true).await;
+        true
+    }
+
+    async fn insert(&self, trigger: String, spec: CommandSpec, command: BotCommandType, external: bool) {
+        log_debug!("Adding command: {} for {}", trigger, spec.permission);
+        for alias in &spec.aliases {
+            self.add_alias(alias, &trigger).await;
+        }
+        self.commands.write().await.insert(trigger, BotCommand {
+            handler: command,
+            spec,
+            external,
+        });
     }
 
-    pub async fn run_command(&self, command: &str, message: IrcMessage) -> Result<()> {
+    // Removes an external command and its aliases, false when there is no such external command.
+    // Built-in commands are never removed.
+    pub async fn remove_external(&self, trigger: &str) -> bool {
+        let mut commands = self.commands.write().await;
+        if !commands.get(trigger).is_some_and(|command| command.external) {
+            return false;
+        }
+        log_debug!("Removing command: {}", trigger);
+        self.aliases.write().await.retain(|_, target| target != trigger);
+        commands.remove(trigger).is_some()
+    }
+
+    // Whether the trigger, or the command it is an alias of, is a built-in command
+    pub async fn is_builtin(&self, trigger: &str) -> bool {
+        let name = self.resolve_alias(trigger).await;
+        self.commands
+            .read()
+            .await
+            .get(&name)
+            .is_some_and(|command| !command.external)
+    }
+
+    // Called by each module of BUILTIN_MODULES once its commands are registered
+    pub async fn builtins_registered(&self, module: &'static str) {
+        self.ready_modules.write().await.insert(module);
+        self.modules_notify.notify_waiters();
+    }
+
+    // Waits for the modules of BUILTIN_MODULES to register their commands, or for the timeout
+    pub async fn wait_for_builtins(&self) {
+        let all_ready = async {
+            loop {
+                // Created before the check, so a module getting ready in between is not missed
+                let notified = self.modules_notify.notified();
+                let ready = self.ready_modules.read().await;
+                if BUILTIN_MODULES.iter().all(|module| ready.contains(module)) {
+                    return;
+                }
+                drop(ready);
+                notified.await;
+            }
+        };
+        if tokio::time::timeout(BUILTIN_MODULES_TIMEOUT, all_ready).await.is_err() {
+            let ready = self.ready_modules.read().await;
+            let missing = BUILTIN_MODULES
+                .iter()
+                .filter(|module| !ready.contains(*module))
+                .copied()
+                .collect::<Vec<_>>();
+            log_warning!("Built-in commands of {} not registered yet", missing.join(", "));
+        }
+    }
+
+    pub async fn add_alias(&self, alias: impl Into<String>, trigger: impl Into<String>) {
+        let (alias, trigger) = (alias.into(), trigger.into());
+        log_debug!("Adding alias: {} for {}", alias, trigger);
+        self.aliases.write().await.insert(alias, trigger);
+    }
+
+    async fn resolve_alias(&self, command: &str) -> String {
+        self.aliases
+            .read()
+            .await
+            .get(command)
+            .cloned()
+            .unwrap_or_else(|| command.to_string())
+    }
+
+    pub async fn has_command(&self, command: &str) -> bool {
+        let name = self.resolve_alias(command).await;
+        self.commands.read().await.contains_key(&name)
+    }
+
+    // Runs the command line of a message, the text after the prefix
+    pub async fn run_command(&self, command_line: &str, message: IrcMessage) -> Result<()> {
+        let command = command_line.split_whitespace().next().unwrap_or_default();
         log_trace!("Running command: {}", command);
-        if let Some(func) = self.commands.read().await.get(command) {
-            func(message).await?;
+        let name = self.resolve_alias(command).await;
+        // Cloned so the commands are not locked while the command runs
+        let Some(bot_command) = self.commands.read().await.get(&name).cloned() else {
+            return Ok(());
+        };
+
+        let permission = permission_for(&message.destination, &name, &bot_command.spec);
+        if !permission.allows(&message) {
+            log_debug!("{} is not allowed to run {}", message.sender, name);
+            deny(&message, permission).await;
+            return Ok(());
+        }
+
+        let subcommand = bot_command.spec.subcommand(command_line);
+        if let Some(subcommand) = subcommand {
+            let permission = subcommand_permission_for(&message.destination, &name, subcommand);
+            if !permission.allows(&message) {
+                log_debug!("{} is not allowed to run {} {}", message.sender, name, subcommand.name);
+                deny(&message, permission).await;
+                return Ok(());
+            }
         }
-        Ok(())
+
+        // Checked before the cooldown, so a mistyped command can be fixed right away
+        let parsed = match subcommand {
+            // Parsed from the subcommand name, which takes the place of the trigger
+            Some(subcommand) => {
+                let line = command_line
+                    .trim_start()
+                    .split_once(char::is_whitespace)
+                    .map_or("", |(_, line)| line);
+                CommandArgs::parse(line, &subcommand.args).map(|args| args.with_subcommand(subcommand.name))
+            }
+            None => match command_line.split_whitespace().nth(1) {
+                Some(action) if !bot_command.spec.subcommands.is_empty() && bot_command.spec.args.is_empty() => {
+                    Err(eyre!("unknown action {}", action))
+                }
+                _ => CommandArgs::parse(command_line, &bot_command.spec.args),
+            },
+        };
+        let args = match parsed {
+            Ok(args) => args,
+            Err(e) => {
+                log_debug!("Invalid arguments for {}: {}", name, e);
+                let usage = subcommand.map_or_else(|| bot_command.spec.usage(), Subcommand::usage);
+                TWITCH_RECEIVER
+                    .send_privmsg(format!(
+                        "@{}, {}, usage: {}{} {}",
+                        message.sender,
+                        e,
+                        BOT_COMMANDS_CONFIG.prefix(&message.destination),
+                        command,
+                        usage
+                    ))
+                    .await;
+                return Ok(());
+            }
+        };
+
+        let cooldown = cooldown_for(&name, &bot_command.spec);
+        if !cooldown.is_none() && message.role() < COMMAND_COOLDOWNS.bypass_role {
+            let mut cooldowns = self.cooldowns.write().await;
+            if let Some(left) = cooldowns.check(&name, &message.sender, cooldown) {
+                log_debug!("{} is on cooldown for {}, {:?} left", name, message.sender, left);
+                let reply = COMMAND_COOLDOWNS.reply
+                    && cooldowns.should_reply(&message.sender, COMMAND_COOLDOWNS.reply_interval_secs);
+                drop(cooldowns);
+                if reply {
+                    TWITCH_RECEIVER
+                        .send_privmsg(format!(
+                            "@{}, {}{} is on cooldown, {} seconds left",
+                            message.sender,
+                            BOT_COMMANDS_CONFIG.prefix(&message.destination),
+                            command,
+                            left.as_secs().max(1)
+                        ))
+                        .await;
+                }
+                return Ok(());
+            }
+        }
+
+        (bot_command.handler)(message, args).await
     }
 }
 
+// Permission of a command on the channel, the configured override or the registered one
+fn permission_for<'a>(channel: &str, name: &str, spec: &'a CommandSpec) -> &'a Permission {
+    COMMAND_PERMISSIONS.get(channel, name).unwrap_or(&spec.permission)
+}
+
+// Overridden as "<command> <subcommand>", e.g. "audio device"
+fn subcommand_permission_for<'a>(channel: &str, name: &str, subcommand: &'a Subcommand) -> &'a Permission {
+    COMMAND_PERMISSIONS
+        .get(channel, &format!("{} {}", name, subcommand.name))
+        .unwrap_or(&subcommand.permission)
+}
+
+fn cooldown_for(name: &str, spec: &CommandSpec) -> Cooldown {
+    COMMAND_COOLDOWNS.overrides.get(name).copied().unwrap_or(spec.cooldown)
+}
+
 pub async fn start() -> Result<()> {
     let mut test_broadcast_rx = TWITCH_BROADCAST.subscribe_broadcast().await;
+    COMMAND_PERMISSIONS.warm_up();
+    COMMAND_COOLDOWNS.warm_up();
+    BOT_COMMANDS_CONFIG.warm_up();
+    VARIABLES.read().await.warm_up();
 
+    // The command list can be spoken, so it is kept from being repeated back to back
     BOT_COMMANDS
-        .add_command(
+        .register(
             "help",
-            Arc::new(|irc_message| Box::pin(bot_cmd_list_all_commands(irc_message))),
+            CommandSpec {
+                // Per user, so a viewer spamming the spoken list doesn't lock it for the others
+                cooldown: Cooldown {
+                    global_secs: 0,
+                    user_secs: 30,
+                },
+                description: "Lists the commands you can use, or describes one".into(),
+                args: vec![ArgSpec::optional("topic", ArgKind::Word)],
+                usage: "[page|command]".into(),
+                aliases: vec!["commands".into()],
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_list_all_commands(irc_message, args))),
+        )
+        .await;
+
+    BOT_COMMANDS
+        .register(
+            "die",
+            CommandSpec {
+                permission: Role::Broadcaster.into(),
+                description: "Stops the Twitch client, to test its restart".into(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_die(irc_message, args))),
+        )
+        .await;
+
+    BOT_COMMANDS
+        .register(
+            "reload",
+            CommandSpec {
+                permission: Role::Moderator.into(),
+                description: format!("Reloads the custom commands from {}", EXTERNAL_COMMANDS_FILE),
+                category: "custom".into(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_reload(irc_message, args))),
+        )
+        .await;
+
+    BOT_COMMANDS
+        .register(
+            "cmd",
+            CommandSpec {
+                permission: Role::Moderator.into(),
+                description: "Adds, edits or deletes the custom commands".into(),
+                args: vec![
+                    ArgSpec::required("action", ArgKind::Word),
+                    ArgSpec::optional("name", ArgKind::Word),
+                    ArgSpec::optional("value", ArgKind::Rest),
+                ],
+                usage: CUSTOM_COMMAND_USAGE.into(),
+                category: "custom".into(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_custom_command(irc_message, args))),
         )
         .await;
 
-    // BOT_COMMANDS
-    //     .add_command("die", Arc::new(|irc_message| Box::pin(die(irc_message))))
-    //     .await;
+    BOT_COMMANDS
+        .register(
+            "var",
+            CommandSpec {
+                permission: Role::Moderator.into(),
+                description: "Shows or changes a variable of the custom commands".into(),
+                args: vec![
+                    ArgSpec::required("name", ArgKind::Word),
+                    ArgSpec::optional("action", ArgKind::Word),
+                    ArgSpec::optional("value", ArgKind::Rest),
+                ],
+                usage: VARIABLE_USAGE.into(),
+                category: "custom".into(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_variable(irc_message, args))),
+        )
+        .await;
 
     let ext_bot_commands = ExternalBotCommands::init();
     ext_bot_commands.reg_ext_bot_cmd().await?;
@@ -62,14 +720,13 @@ pub async fn start() -> Result<()> {
     // Read all broadcasted commands from Twitch_client
     while let Ok(ret_val) = test_broadcast_rx.recv().await {
         match ret_val.command.as_str() {
-            "PRIVMSG" if ret_val.payload.starts_with(BOT_COMMAND_PREFIX) => {
-                let command = ret_val
-                    .payload
-                    .split_whitespace()
-                    .next()
-                    .unwrap()
-                    .trim_start_matches(BOT_COMMAND_PREFIX);
-                BOT_COMMANDS.run_command(command, ret_val.clone()).await?;
+            "PRIVMSG" => {
+                if let Some(command_line) = BOT_COMMANDS_CONFIG
+                    .command_line(&ret_val.destination, &ret_val.payload)
+                    .await
+                {
+                    BOT_COMMANDS.run_command(command_line, ret_val.clone()).await?;
+                }
             }
             _ => {}
         };
@@ -78,20 +735,233 @@ pub async fn start() -> Result<()> {
     Ok(())
 }
 
-pub async fn bot_cmd_list_all_commands(_message: IrcMessage) -> Result<()> {
-    let triggers = BOT_COMMANDS
+async fn deny(message: &IrcMessage, permission: &Permission) {
+    TWITCH_RECEIVER
+        .send_privmsg(format!("@{}, only {} can use this command", message.sender, permission))
+        .await;
+}
+
+// !die makes the Twitch client task fail, so the task manager restarts it
+pub async fn bot_cmd_die(_message: IrcMessage, _args: CommandArgs) -> Result<()> {
+    log_error!("I'm dying cruel world");
+    TWITCH_DIE.notify_one();
+    Ok(())
+}
+
+// !help [page] lists the commands the sender may run, !help <command> describes one
+pub async fn bot_cmd_list_all_commands(message: IrcMessage, args: CommandArgs) -> Result<()> {
+    let topic = args.text("topic");
+    let ret_val = match topic {
+        Some(topic) if topic.parse::<usize>().is_err() => {
+            command_details(&message, BOT_COMMANDS_CONFIG.trim_prefix(&message.destination, topic)).await
+        }
+        _ => command_list(&message, topic.and_then(|topic| topic.parse().ok()).unwrap_or(1)).await,
+    };
+
+    if BOT_COMMANDS_CONFIG.speak_help {
+        TTS_QUEUE
+            .push_back(voice_msg(&ret_val, &TWITCH_BOT_INFO.nick_name().await).await)
+            .await;
+    }
+    TWITCH_RECEIVER.send_privmsg(ret_val).await;
+    Ok(())
+}
+
+async fn command_list(message: &IrcMessage, page: usize) -> String {
+    let mut entries = BOT_COMMANDS
         .commands
         .read()
         .await
         .iter()
-        .map(|(trigger, _)| format!("{}{}", BOT_COMMAND_PREFIX, trigger))
-        .collect::<Vec<_>>()
-        .join(", ");
+        .filter(|(name, command)| permission_for(&message.destination, name, &command.spec).allows(message))
+        .map(|(name, command)| (command.spec.category().to_string(), name.clone()))
+        .collect::<Vec<_>>();
+    entries.sort();
 
-    let ret_val = format!("Available commands: {}", triggers);
-    TTS_QUEUE
-        .push_back(voice_msg(&ret_val, &TWITCH_BOT_INFO.nick_name().await).await)
-        .await;
-    TWITCH_RECEIVER.send_privmsg(ret_val).await;
-    Ok(())
+    // Every page has to fit in a single chat line
+    let prefix = BOT_COMMANDS_CONFIG.prefix(&message.destination);
+    let footer = format!(" | {}help <command> for details", prefix);
+    let header_len = format!("@{}, commands (page 999/999): ", message.sender).len();
+    let pages = paginate(&entries, prefix, TWITCH_MAX_MSG_LINE_LENGTH - header_len - footer.len());
+    if pages.is_empty() {
+        return format!("@{}, there is no command you can use", message.sender);
+    }
+
+    let page = page.clamp(1, pages.len());
+    format!(
+        "@{}, commands (page {}/{}): {}{}",
+        message.sender,
+        page,
+        pages.len(),
+        pages[page - 1],
+        footer
+    )
+}
+
+// Packs the sorted (category, trigger) entries into pages of at most max_len characters,
+// e.g. "audio: !pause, !resume | voice: !voice"
+fn paginate(entries: &[(String, String)], prefix: &str, max_len: usize) -> Vec<String> {
+    let mut pages = Vec::<String>::new();
+    let mut page = String::new();
+    let mut page_category = "";
+    for (category, trigger) in entries {
+        let command = format!("{}{}", prefix, trigger);
+        let piece = match (page.is_empty(), page_category == category) {
+            (true, _) => format!("{}: {}", category, command),
+            (false, true) => format!(", {}", command),
+            (false, false) => format!(" | {}: {}", category, command),
+        };
+        if !page.is_empty() && page.len() + piece.len() > max_len {
+            pages.push(std::mem::take(&mut page));
+            page = format!("{}: {}", category, command);
+        } else {
+            page.push_str(&piece);
+        }
+        page_category = category;
+    }
+    if !page.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+async fn command_details(message: &IrcMessage, command: &str) -> String {
+    let prefix = BOT_COMMANDS_CONFIG.prefix(&message.destination);
+    let name = BOT_COMMANDS.resolve_alias(command).await;
+    let Some(spec) = BOT_COMMANDS
+        .commands
+        .read()
+        .await
+        .get(&name)
+        .map(|command| command.spec.clone())
+    else {
+        return format!("@{}, unknown command {}{}", message.sender, prefix, command);
+    };
+
+    let mut aliases = BOT_COMMANDS
+        .aliases
+        .read()
+        .await
+        .iter()
+        .filter(|(_, target)| **target == name)
+        .map(|(alias, _)| format!("{}{}", prefix, alias))
+        .collect::<Vec<_>>();
+    aliases.sort();
+
+    let mut details = vec![format!("{}{} {}", prefix, name, spec.usage()).trim_end().to_string()];
+    if !spec.description.is_empty() {
+        details.push(spec.description.clone());
+    }
+    if !aliases.is_empty() {
+        details.push(format!("aliases: {}", aliases.join(", ")));
+    }
+    let permission = permission_for(&message.destination, &name, &spec);
+    details.push(format!("for {}", permission));
+    for subcommand in &spec.subcommands {
+        let subcommand_permission = subcommand_permission_for(&message.destination, &name, subcommand);
+        if subcommand_permission != permission {
+            details.push(format!("{} for {}", subcommand.name, subcommand_permission));
+        }
+    }
+    let cooldown = cooldown_for(&name, &spec);
+    if !cooldown.is_none() {
+        details.push(format!("cooldown: {}", cooldown));
+    }
+    format!("@{}, {}", message.sender, details.join(" | "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Cooldown = Cooldown {
+        global_secs: 5,
+        user_secs: 30,
+    };
+
+    #[test]
+    fn global_cooldown_applies_to_everyone() {
+        let mut tracker = CooldownTracker::default();
+        let start = Instant::now();
+        assert_eq!(tracker.check_at(start, "meow", "alice", COOLDOWN), None);
+        let left = tracker.check_at(start + Duration::from_secs(2), "meow", "bob", COOLDOWN);
+        assert_eq!(left, Some(Duration::from_secs(3)));
+        assert_eq!(
+            tracker.check_at(start + Duration::from_secs(5), "meow", "bob", COOLDOWN),
+            None
+        );
+    }
+
+    #[test]
+    fn user_cooldown_applies_to_the_sender_only() {
+        let mut tracker = CooldownTracker::default();
+        let start = Instant::now();
+        tracker.check_at(start, "meow", "alice", COOLDOWN);
+        let later = start + Duration::from_secs(10);
+        assert_eq!(
+            tracker.check_at(later, "meow", "alice", COOLDOWN),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(tracker.check_at(later, "meow", "bob", COOLDOWN), None);
+    }
+
+    #[test]
+    fn cooldowns_are_per_command() {
+        let mut tracker = CooldownTracker::default();
+        let start = Instant::now();
+        tracker.check_at(start, "meow", "alice", COOLDOWN);
+        assert_eq!(tracker.check_at(start, "hug", "alice", COOLDOWN), None);
+    }
+
+    #[test]
+    fn expired_runs_are_pruned() {
+        let mut tracker = CooldownTracker::default();
+        let start = Instant::now();
+        tracker.check_at(start, "meow", "alice", COOLDOWN);
+        tracker.check_at(start + Duration::from_secs(6), "meow", "bob", COOLDOWN);
+        assert_eq!(tracker.user.len(), 2);
+        tracker.check_at(start + Duration::from_secs(31), "meow", "carol", COOLDOWN);
+        let users = tracker.user.keys().map(|(_, user)| user.as_str()).collect::<Vec<_>>();
+        assert!(!users.contains(&"alice"), "{:?}", users);
+        assert_eq!(users.len(), 2);
+    }
+
+    #[test]
+    fn no_user_cooldown_keeps_no_runs() {
+        let mut tracker = CooldownTracker::default();
+        let cooldown = Cooldown {
+            global_secs: 5,
+            user_secs: 0,
+        };
+        tracker.check_at(Instant::now(), "meow", "alice", cooldown);
+        assert!(tracker.user.is_empty());
+    }
+
+    fn entries(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        entries
+            .iter()
+            .map(|(category, trigger)| (category.to_string(), trigger.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn paginate_groups_by_category() {
+        let entries = entries(&[("audio", "pause"), ("audio", "resume"), ("voice", "voice")]);
+        assert_eq!(paginate(&entries, "!", 500), vec![
+            "audio: !pause, !resume | voice: !voice".to_string()
+        ]);
+    }
+
+    #[test]
+    fn paginate_repeats_the_category_on_a_new_page() {
+        let entries = entries(&[("audio", "pause"), ("audio", "resume"), ("audio", "skip")]);
+        let pages = paginate(&entries, "!", 24);
+        assert_eq!(pages, vec!["audio: !pause, !resume", "audio: !skip"]);
+        assert!(pages.iter().all(|page| page.len() <= 24));
+    }
+
+    #[test]
+    fn paginate_without_entries_has_no_pages() {
+        assert!(paginate(&[], "!", 500).is_empty());
+    }
 }
@@ -1,31 +1,138 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, SystemTime};
 
-use curl::easy::Easy;
-use eyre::Result;
+use eyre::{Result, eyre};
 use futures::executor::block_on;
 use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, RwLock};
 
 use crate::CONFIG_DIR;
-use crate::audio_player::TTS_AUDIO_QUEUE;
-use crate::bot_commands::{BOT_COMMAND_PREFIX, BOT_COMMANDS};
+use crate::audio_player::{AudioSource, enqueue};
+use crate::bot_commands::{BOT_COMMANDS, BOT_COMMANDS_CONFIG, CommandSpec, Cooldown, Permission};
+use crate::command_args::{ArgKind, ArgSpec, CommandArgs};
 use crate::common::PersistentConfig;
 use crate::irc_parser::IrcMessage;
+use crate::sound_library::SOUND_LIBRARY;
+use crate::templates::{self, Template, TemplateContext};
 use crate::tts::{TTS_QUEUE, voice_msg};
 use crate::twitch_client::{TWITCH_BOT_INFO, TWITCH_RECEIVER};
 
 pub static EXTERNAL_COMMANDS_FILE: &str = "ExternalBotCommands.toml";
 
-#[derive(Deserialize, Debug, Clone, Serialize)]
+// Registered external commands, to find what changed on reload and to edit them from chat
+static EXTERNAL_COMMANDS: LazyLock<RwLock<ExternalBotCommands>> = LazyLock::new(|| {
+    RwLock::new(ExternalBotCommands {
+        commands: HashMap::new(),
+    })
+});
+
+// Held while the commands are checked, registered and saved, so a reload and the !cmd edits don't
+// overwrite each other's changes
+static EDIT_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+pub const CUSTOM_COMMAND_USAGE: &str =
+    "add|edit <name> <reply> | del <name> | alias <name> <alias> | sound <name> <url|file|none>";
+
+// How often the file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+// Time without changes before a modified file is reloaded, editors often save in several writes
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Serialize)]
 struct ExternalBotCommand {
     activation_pattern: String,
     aliases: Option<Vec<String>>,
     need_arg: bool,
     custom_audio_url: String,
+    // Sound library names, local files or URLs, one is picked at random with custom_audio_url
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sounds: Vec<String>,
+    // Queue rule of the custom audio, command_sound or alert
+    #[serde(default = "default_audio_source")]
+    audio_source: AudioSource,
     replay_text: String,
+    // Shown by !help <command>
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    description: String,
+    // Who may run the command, everyone when missing
+    #[serde(default)]
+    permission: Permission,
+    // Seconds between two runs by anyone (global_secs) and by the same user (user_secs)
+    #[serde(default)]
+    cooldown: Cooldown,
     // play_mode: String,
 }
 
+fn default_audio_source() -> AudioSource {
+    AudioSource::CommandSound
+}
+
+// Replies with an invalid template are rejected later by the validation
+fn requires_args(reply: &str) -> bool {
+    Template::parse(reply).is_ok_and(|template| template.requires_args())
+}
+
+impl ExternalBotCommand {
+    // Command added from chat, a text reply for everyone
+    fn text_reply(name: &str, reply: &str) -> Self {
+        Self {
+            activation_pattern: name.into(),
+            aliases: None,
+            need_arg: requires_args(reply),
+            custom_audio_url: String::new(),
+            sounds: Vec::new(),
+            audio_source: default_audio_source(),
+            replay_text: reply.into(),
+            description: String::new(),
+            permission: Permission::default(),
+            cooldown: Cooldown::default(),
+        }
+    }
+
+    fn template(&self) -> Result<Template> {
+        Template::parse(&self.replay_text)
+    }
+
+    fn sound_references(&self) -> Vec<String> {
+        let mut references = self.sounds.clone();
+        if !self.custom_audio_url.is_empty() {
+            references.push(self.custom_audio_url.clone());
+        }
+        references
+    }
+
+    fn triggers(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.activation_pattern).chain(self.aliases.iter().flatten())
+    }
+}
+
+// Commands changed by a reload
+#[derive(Debug, Default)]
+pub struct ReloadSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    // Commands the registry refused, they are not kept as registered
+    pub failed: Vec<String>,
+}
+
+impl Display for ReloadSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added, {} updated, {} removed",
+            self.added, self.updated, self.removed
+        )?;
+        if !self.failed.is_empty() {
+            write!(f, ", not registered: {}", self.failed.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct ExternalBotCommands {
     commands: HashMap<String, ExternalBotCommand>,
@@ -40,7 +147,12 @@ impl Default for ExternalBotCommands {
             aliases: None,
             need_arg: false,
             custom_audio_url: "".into(),
+            sounds: Vec::new(),
+            audio_source: default_audio_source(),
             replay_text: "Hi there {SENDER} this is the reply to your test command".to_string(),
+            description: "Replies to check that the bot is listening".into(),
+            permission: Permission::default(),
+            cooldown: Cooldown::default(),
         };
 
         let cmd_meow = ExternalBotCommand {
@@ -48,7 +160,15 @@ impl Default for ExternalBotCommands {
             aliases: Some(vec!["cat".into()]),
             need_arg: false,
             custom_audio_url: "https://www.myinstants.com/media/sounds/m-e-o-w.mp3".into(),
+            sounds: Vec::new(),
+            audio_source: default_audio_source(),
             replay_text: "".into(),
+            description: "Plays a meow".into(),
+            permission: Permission::default(),
+            cooldown: Cooldown {
+                global_secs: 0,
+                user_secs: 30,
+            },
         };
 
         let cmd_for_president: ExternalBotCommand = ExternalBotCommand {
@@ -56,13 +176,35 @@ impl Default for ExternalBotCommands {
             aliases: None,
             need_arg: true,
             custom_audio_url: "".into(),
+            sounds: Vec::new(),
+            audio_source: default_audio_source(),
             replay_text: "{ARG} for President!".into(),
+            description: "Nominates someone for president".into(),
+            permission: Permission::default(),
+            cooldown: Cooldown::default(),
+        };
+
+        let cmd_laugh = ExternalBotCommand {
+            activation_pattern: "laugh".into(),
+            aliases: None,
+            need_arg: false,
+            custom_audio_url: "".into(),
+            sounds: vec!["togglebit-laugh".into()],
+            audio_source: default_audio_source(),
+            replay_text: "".into(),
+            description: "Plays a laugh".into(),
+            permission: Permission::default(),
+            cooldown: Cooldown {
+                global_secs: 0,
+                user_secs: 30,
+            },
         };
 
         let mut commands = HashMap::new();
         commands.insert("test".into(), cmd_test);
         commands.insert("meow".into(), cmd_meow);
         commands.insert("for_president".into(), cmd_for_president);
+        commands.insert("laugh".into(), cmd_laugh);
 
         Self { commands }
     }
@@ -75,78 +217,373 @@ impl ExternalBotCommands {
     }
 
     pub async fn reg_ext_bot_cmd(&self) -> Result<()> {
-        for ext_cmd in self.commands.values() {
-            ext_bot_cmd(ext_cmd.clone()).await?;
+        templates::warm_up();
+        // The triggers are checked against every built-in command
+        BOT_COMMANDS.wait_for_builtins().await;
+        let _edit = EDIT_LOCK.lock().await;
+
+        // At startup there is nothing to fall back to, so only the invalid commands are left out.
+        // Sorted, so the same command wins a duplicated trigger on every start.
+        let mut names = self.commands.keys().collect::<Vec<_>>();
+        names.sort();
+        let mut valid = ExternalBotCommands {
+            commands: HashMap::new(),
+        };
+        let mut triggers = HashSet::new();
+        for name in names {
+            let command = &self.commands[name];
+            match validate_command(name, command, &mut triggers).await {
+                Ok(()) => {
+                    valid.commands.insert(name.clone(), command.clone());
+                }
+                Err(e) => log_error!("{}: {}, the command is not registered", EXTERNAL_COMMANDS_FILE, e),
+            }
+        }
+        let summary = valid.sync().await?;
+        log!("External commands registered: {}", summary);
+        Ok(())
+    }
+
+    // Reads the file again and applies the changes. A file with errors leaves the commands as they are.
+    pub async fn reload() -> Result<ReloadSummary> {
+        let _edit = EDIT_LOCK.lock().await;
+        let commands = ExternalBotCommands::try_load(CONFIG_DIR).await?;
+        commands.validate().await?;
+        commands.sync().await
+    }
+
+    // Name in the file of the command with this trigger
+    fn key_of(&self, trigger: &str) -> Option<String> {
+        self.commands
+            .iter()
+            .find(|(_, command)| command.triggers().any(|known| known == trigger))
+            .map(|(key, _)| key.clone())
+    }
+
+    fn find_mut(&mut self, trigger: &str) -> Result<&mut ExternalBotCommand> {
+        let key = self
+            .key_of(trigger)
+            .ok_or_else(|| eyre!("{} is not a custom command", trigger))?;
+        Ok(self.commands.get_mut(&key).expect("key_of returns existing keys"))
+    }
+
+    fn by_pattern(&self) -> HashMap<&String, &ExternalBotCommand> {
+        self.commands
+            .values()
+            .filter(|command| !command.activation_pattern.is_empty())
+            .map(|command| (&command.activation_pattern, command))
+            .collect()
+    }
+
+    async fn validate(&self) -> Result<()> {
+        let mut triggers = HashSet::new();
+        for (name, command) in &self.commands {
+            validate_command(name, command, &mut triggers).await?;
         }
         Ok(())
     }
+
+    // Registers the new and changed commands and removes the missing ones
+    async fn sync(&self) -> Result<ReloadSummary> {
+        let mut current = EXTERNAL_COMMANDS.write().await;
+        let registered = current.by_pattern();
+        let commands = self.by_pattern();
+
+        let mut summary = ReloadSummary::default();
+        let mut changed = Vec::new();
+        let mut added = HashSet::new();
+        for (pattern, command) in &commands {
+            match registered.get(pattern) {
+                Some(old) if old == command => {}
+                Some(_) => changed.push(*command),
+                None => {
+                    added.insert(*pattern);
+                    changed.push(*command);
+                }
+            }
+        }
+
+        // Everything is removed before registering, so an alias can move from a command to another
+        for pattern in registered.keys() {
+            match commands.get(pattern) {
+                None => {
+                    summary.removed += 1;
+                    BOT_COMMANDS.remove_external(pattern).await;
+                }
+                Some(command) if changed.contains(command) => {
+                    BOT_COMMANDS.remove_external(pattern).await;
+                }
+                Some(_) => {}
+            }
+        }
+        let mut sounds = Vec::new();
+        for command in &changed {
+            let pattern = &command.activation_pattern;
+            if !ext_bot_cmd((*command).clone()).await {
+                summary.failed.push(pattern.clone());
+                continue;
+            }
+            if added.contains(pattern) {
+                summary.added += 1;
+            } else {
+                summary.updated += 1;
+            }
+            sounds.extend(command.sound_references());
+        }
+        summary.failed.sort();
+
+        // Only what the registry holds, so the next diff starts from the real state
+        let mut applied = self.clone();
+        applied
+            .commands
+            .retain(|_, command| !summary.failed.contains(&command.activation_pattern));
+        *current = applied;
+        drop(current);
+        SOUND_LIBRARY.preload(sounds).await;
+        Ok(summary)
+    }
 }
 
-async fn ext_bot_cmd(command: ExternalBotCommand) -> Result<()> {
+// Checks a command, triggers collects the ones of the commands already checked
+async fn validate_command<'a>(
+    name: &str,
+    command: &'a ExternalBotCommand,
+    triggers: &mut HashSet<&'a String>,
+) -> Result<()> {
+    command
+        .template()
+        .map_err(|e| eyre!("command {} has an invalid reply: {}", name, e))?;
+    let mut own = HashSet::new();
+    for trigger in command.triggers() {
+        if trigger.is_empty() || trigger.contains(char::is_whitespace) {
+            return Err(eyre!("command {} has an invalid trigger \"{}\"", name, trigger));
+        }
+        if triggers.contains(trigger) || !own.insert(trigger) {
+            return Err(eyre!("trigger {} is used by more than one command", trigger));
+        }
+        // Built-in commands can't be replaced
+        if BOT_COMMANDS.is_builtin(trigger).await {
+            return Err(eyre!("trigger {} of command {} is a built-in command", trigger, name));
+        }
+    }
+    triggers.extend(own);
+    Ok(())
+}
+
+// Reloads the external commands when their file changes
+pub async fn watch() -> Result<()> {
+    let path = ExternalBotCommands::config_path(CONFIG_DIR);
+    let mut last_modified = modified_time(&path).await;
+    loop {
+        tokio::time::sleep(WATCH_INTERVAL).await;
+        let mut modified = modified_time(&path).await;
+        if modified == last_modified {
+            continue;
+        }
+        loop {
+            tokio::time::sleep(RELOAD_DEBOUNCE).await;
+            let latest = modified_time(&path).await;
+            if latest == modified {
+                break;
+            }
+            modified = latest;
+        }
+        last_modified = modified;
+
+        // A deleted file keeps the current commands
+        if modified.is_none() {
+            continue;
+        }
+        match ExternalBotCommands::reload().await {
+            Ok(summary) => log!("{} reloaded: {}", EXTERNAL_COMMANDS_FILE, summary),
+            Err(e) => log_error!(
+                "{} not reloaded, keeping the current commands: {}",
+                EXTERNAL_COMMANDS_FILE,
+                e
+            ),
+        }
+    }
+}
+
+async fn modified_time(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+pub async fn bot_cmd_reload(message: IrcMessage, _args: CommandArgs) -> Result<()> {
+    let reply = match ExternalBotCommands::reload().await {
+        Ok(summary) => format!("external commands reloaded: {}", summary),
+        Err(e) => {
+            log_error!(
+                "{} not reloaded, keeping the current commands: {}",
+                EXTERNAL_COMMANDS_FILE,
+                e
+            );
+            format!("external commands not reloaded, {}", e)
+        }
+    };
+    TWITCH_RECEIVER
+        .send_privmsg(format!("@{}, {}", message.sender, reply))
+        .await;
+    Ok(())
+}
+
+// Registers a command, false when the registry refuses it
+async fn ext_bot_cmd(command: ExternalBotCommand) -> bool {
     if command.activation_pattern.is_empty() {
         log_error!("Activation command is empty, skipping command {:?}", &command);
-        return Ok(());
+        return false;
     }
 
+    // Checked by the validation, a reply that doesn't parse is sent as it is
+    let template = Arc::new(command.template().unwrap_or_else(|e| {
+        log_error!("Invalid reply of {}: {}", command.activation_pattern, e);
+        Template::text(&command.replay_text)
+    }));
     let inner_command = command.clone();
     BOT_COMMANDS
-        .add_command(
+        .register_external(
             inner_command.activation_pattern.clone(),
-            Arc::new(move |irc_message| Box::pin(handle_command(irc_message, inner_command.clone()))),
+            CommandSpec {
+                permission: command.permission.clone(),
+                cooldown: command.cooldown,
+                description: command.description.clone(),
+                args: if command.need_arg {
+                    vec![ArgSpec::required("text", ArgKind::Rest)]
+                } else {
+                    Vec::new()
+                },
+                category: "custom".into(),
+                aliases: command.aliases.clone().unwrap_or_default(),
+                ..Default::default()
+            },
+            Arc::new(move |irc_message, args| {
+                Box::pin(handle_command(
+                    irc_message,
+                    args,
+                    inner_command.clone(),
+                    template.clone(),
+                ))
+            }),
         )
+        .await
+}
+
+// !cmd add|edit|del|alias|sound, changes the external commands from chat and saves them
+pub async fn bot_cmd_custom_command(message: IrcMessage, args: CommandArgs) -> Result<()> {
+    let action = args.text("action").unwrap_or_default();
+    let name = args
+        .text("name")
+        .map(|name| BOT_COMMANDS_CONFIG.trim_prefix(&message.destination, name));
+    let reply = match edit_commands(action, name, args.text("value")).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            log_debug!("!cmd {} failed: {}", action, e);
+            e.to_string()
+        }
+    };
+    TWITCH_RECEIVER
+        .send_privmsg(format!("@{}, {}", message.sender, reply))
         .await;
+    Ok(())
+}
 
-    if let Some(aliases) = command.clone().aliases {
-        for alias in aliases {
-            let inner_command = command.clone();
-            BOT_COMMANDS
-                .add_command(
-                    alias.clone(),
-                    Arc::new(move |irc_message| Box::pin(handle_command(irc_message, inner_command.clone()))),
-                )
-                .await;
+async fn edit_commands(action: &str, name: Option<&str>, value: Option<&str>) -> Result<String> {
+    let name = name.ok_or_else(|| eyre!("missing command name"))?;
+    // Until the edit is saved, so two edits in a row both end up in the file
+    let _edit = EDIT_LOCK.lock().await;
+    let mut commands = EXTERNAL_COMMANDS.read().await.clone();
+    let reply = match (action, value) {
+        ("add", Some(reply)) => {
+            if commands.key_of(name).is_some() {
+                return Err(eyre!("{} already exists, edit it instead", name));
+            }
+            commands
+                .commands
+                .insert(name.to_string(), ExternalBotCommand::text_reply(name, reply));
+            format!("command {} added", name)
         }
-    }
+        ("edit", Some(reply)) => {
+            let command = commands.find_mut(name)?;
+            command.replay_text = reply.into();
+            command.need_arg = requires_args(reply);
+            format!("command {} updated", command.activation_pattern)
+        }
+        ("del", None) => {
+            let key = commands
+                .key_of(name)
+                .ok_or_else(|| eyre!("{} is not a custom command", name))?;
+            commands.commands.remove(&key);
+            format!("command {} deleted", name)
+        }
+        ("alias", Some(alias)) => {
+            let command = commands.find_mut(name)?;
+            command.aliases.get_or_insert_with(Vec::new).push(alias.into());
+            format!("{} is now an alias of {}", alias, command.activation_pattern)
+        }
+        ("sound", Some("none")) => {
+            let command = commands.find_mut(name)?;
+            command.custom_audio_url.clear();
+            command.sounds.clear();
+            format!("command {} has no sound anymore", command.activation_pattern)
+        }
+        ("sound", Some(sound)) => {
+            // Tried before saving, so a sound that can't be played is reported right away
+            SOUND_LIBRARY.clip(sound, AudioSource::CommandSound).await?;
+            let command = commands.find_mut(name)?;
+            // The only sound of the command, not one more to pick from
+            command.custom_audio_url = sound.into();
+            command.sounds.clear();
+            format!("command {} now plays {}", command.activation_pattern, sound)
+        }
+        _ => return Err(eyre!("usage: {}", CUSTOM_COMMAND_USAGE)),
+    };
 
-    Ok(())
+    commands.validate().await?;
+    let summary = commands.sync().await?;
+    if !summary.failed.is_empty() {
+        return Err(eyre!("{} could not be registered", summary.failed.join(", ")));
+    }
+    commands.save(CONFIG_DIR).await;
+    Ok(reply)
 }
-async fn get_audio_data(url: impl AsRef<str>) -> Vec<u8> {
-    let mut data = Vec::new();
-    let mut easy = Easy::new();
-    let _ = easy.ssl_verify_peer(false);
-    easy.url(url.as_ref()).unwrap();
-    {
-        let mut transfer = easy.transfer();
-        transfer
-            .write_function(|new_data| {
-                data.extend_from_slice(new_data);
-                Ok(new_data.len())
-            })
-            .unwrap();
-        transfer.perform().unwrap();
-    }
-    data
-}
-
-async fn handle_command(irc_message: IrcMessage, command: ExternalBotCommand) -> Result<()> {
+
+async fn handle_command(
+    irc_message: IrcMessage,
+    args: CommandArgs,
+    command: ExternalBotCommand,
+    template: Arc<Template>,
+) -> Result<()> {
     log_debug!("Running command: {}", command.activation_pattern);
 
-    let reply_payload = if command.need_arg {
-        if let Some(arg) = irc_message.payload.split_once(" ").map(|(_, arg)| arg) {
-            command.replay_text.replace("{ARG}", arg)
-        } else {
-            format!(
-                "Hey @{}, you need to provide an argument for {}{} command",
-                &irc_message.sender, BOT_COMMAND_PREFIX, &command.activation_pattern
-            )
-        }
-    } else {
-        command.replay_text.replace("{SENDER}", &irc_message.sender)
-    };
+    // A missing argument is reported with the usage before the command runs
+    let reply_payload = template
+        .render(&TemplateContext {
+            message: &irc_message,
+            args: &args,
+            command: &command.activation_pattern,
+        })
+        .await;
 
-    if !command.custom_audio_url.is_empty() {
-        let audio_data = get_audio_data(&command.custom_audio_url).await;
-        TTS_AUDIO_QUEUE.push_back(audio_data).await;
+    let sounds = command.sound_references();
+    if !sounds.is_empty() {
+        match SOUND_LIBRARY.random_clip(&sounds, command.audio_source).await {
+            Ok(clip) => enqueue(clip).await,
+            Err(e) => {
+                log_error!("Unable to play the sound of {}: {}", command.activation_pattern, e);
+                TWITCH_RECEIVER
+                    .send_privmsg(format!(
+                        "@{}, unable to play the sound of {}{}: {}",
+                        irc_message.sender,
+                        BOT_COMMANDS_CONFIG.prefix(&irc_message.destination),
+                        command.activation_pattern,
+                        e
+                    ))
+                    .await;
+            }
+        }
     }
 
     TTS_QUEUE
@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use eyre::{Result, eyre};
+
+// Type of a command argument, checked before the handler runs
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArgKind {
+    // A single word, or a quoted string with spaces
+    Word,
+    // Everything left on the line, as typed
+    Rest,
+    // A user name, with or without the @ of a mention, resolved to the lowercase nick
+    User,
+    Integer { min: i64, max: i64 },
+    Number { min: f64, max: f64 },
+    // One of the listed words, matched without case
+    Choice(&'static [&'static str]),
+}
+
+impl ArgKind {
+    // Checks a single value, the errors are meant to be shown in chat as they are
+    pub fn parse(&self, name: &str, token: &str) -> Result<ArgValue> {
+        match self {
+            ArgKind::Word | ArgKind::Rest => Ok(ArgValue::Text(token.to_string())),
+            ArgKind::User => {
+                let nick = token.trim_start_matches('@').to_lowercase();
+                if nick.is_empty() || !nick.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    return Err(eyre!("{} is not a user name", token));
+                }
+                Ok(ArgValue::Text(nick))
+            }
+            ArgKind::Integer { min, max } => token
+                .parse::<i64>()
+                .ok()
+                .filter(|value| (*min..=*max).contains(value))
+                .map(ArgValue::Integer)
+                .ok_or_else(|| eyre!("{} must be a whole number from {} to {}", name, min, max)),
+            ArgKind::Number { min, max } => token
+                .parse::<f64>()
+                .ok()
+                .filter(|value| (*min..=*max).contains(value))
+                .map(ArgValue::Number)
+                .ok_or_else(|| eyre!("{} must be a number from {} to {}", name, min, max)),
+            ArgKind::Choice(choices) => choices
+                .iter()
+                .find(|choice| choice.eq_ignore_ascii_case(token))
+                .map(|choice| ArgValue::Text(choice.to_string()))
+                .ok_or_else(|| eyre!("{} must be {}", name, choices.join(" or "))),
+        }
+    }
+}
+
+// A declared argument. Positional arguments are matched in order, named ones are given as name=value anywhere.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub required: bool,
+    pub named: bool,
+}
+
+impl ArgSpec {
+    pub fn required(name: &'static str, kind: ArgKind) -> Self {
+        Self {
+            name,
+            kind,
+            required: true,
+            named: false,
+        }
+    }
+
+    pub fn optional(name: &'static str, kind: ArgKind) -> Self {
+        Self {
+            name,
+            kind,
+            required: false,
+            named: false,
+        }
+    }
+
+    pub fn named(name: &'static str, kind: ArgKind) -> Self {
+        Self {
+            name,
+            kind,
+            required: false,
+            named: true,
+        }
+    }
+}
+
+impl Display for ArgSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match self.kind {
+            ArgKind::Rest => format!("{}...", self.name),
+            ArgKind::Choice(choices) => choices.join("|"),
+            _ => self.name.to_string(),
+        };
+        match (self.named, self.required) {
+            (true, _) => write!(f, "[{}=<value>]", self.name),
+            (false, true) => write!(f, "<{}>", value),
+            (false, false) => write!(f, "[{}]", value),
+        }
+    }
+}
+
+// Usage line of the arguments, e.g. "<user> [voice]"
+pub fn usage(specs: &[ArgSpec]) -> String {
+    specs.iter().map(|spec| spec.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArgValue {
+    Text(String),
+    Integer(i64),
+    Number(f64),
+}
+
+// Arguments of a command run, parsed from the text after the trigger
+#[derive(Debug, Clone, Default)]
+pub struct CommandArgs {
+    // Every word after the trigger, quoted strings are a single token without their quotes
+    tokens: Vec<String>,
+    values: HashMap<&'static str, ArgValue>,
+    // Subcommand picked by the first argument, the tokens and values are the ones after it
+    subcommand: Option<&'static str>,
+}
+
+impl CommandArgs {
+    // Parses the message payload, trigger included. Without specs the tokens are not checked.
+    pub fn parse(payload: &str, specs: &[ArgSpec]) -> Result<Self> {
+        let text = payload
+            .trim_start()
+            .split_once(char::is_whitespace)
+            .map_or("", |(_, text)| text);
+        let tokens = tokenize(text);
+        let mut args = CommandArgs {
+            tokens: tokens.iter().map(|token| token.value.clone()).collect(),
+            values: HashMap::new(),
+            subcommand: None,
+        };
+        if specs.is_empty() {
+            return Ok(args);
+        }
+
+        // Named arguments are taken out first, so they don't shift the positional ones
+        let mut positional = Vec::new();
+        let mut has_named = false;
+        for token in &tokens {
+            let named = token
+                .value
+                .split_once('=')
+                .filter(|_| !token.quoted)
+                .and_then(|(name, value)| Some((specs.iter().find(|spec| spec.named && spec.name == name)?, value)));
+            match named {
+                Some((spec, value)) => {
+                    has_named = true;
+                    token.check_quotes()?;
+                    args.values.insert(spec.name, spec.kind.parse(spec.name, value)?);
+                }
+                None => positional.push(token),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        for spec in specs.iter().filter(|spec| !spec.named) {
+            let Some(token) = positional.next() else {
+                if spec.required {
+                    return Err(eyre!("missing {}", spec.name));
+                }
+                break;
+            };
+            if spec.kind == ArgKind::Rest {
+                // Taken as typed, quotes and spacing included, unless named arguments are mixed in.
+                // It has to be the last argument.
+                let rest = if has_named {
+                    std::iter::once(token)
+                        .chain(positional)
+                        .map(|token| token.value.as_str())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                } else {
+                    text[token.start..].trim_end().to_string()
+                };
+                args.values.insert(spec.name, spec.kind.parse(spec.name, &rest)?);
+                return Ok(args);
+            }
+            token.check_quotes()?;
+            args.values.insert(spec.name, spec.kind.parse(spec.name, &token.value)?);
+        }
+        if let Some(extra) = positional.next() {
+            return Err(eyre!("unexpected argument {}", extra.value));
+        }
+        Ok(args)
+    }
+
+    pub fn with_subcommand(mut self, name: &'static str) -> Self {
+        self.subcommand = Some(name);
+        self
+    }
+
+    pub fn tokens(&self) -> &[String] {
+        &self.tokens
+    }
+
+    pub fn subcommand(&self) -> Option<&'static str> {
+        self.subcommand
+    }
+
+    // Word, Rest and User arguments
+    pub fn text(&self, name: &str) -> Option<&str> {
+        match self.values.get(name)? {
+            ArgValue::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn integer(&self, name: &str) -> Option<i64> {
+        match self.values.get(name)? {
+            ArgValue::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn number(&self, name: &str) -> Option<f64> {
+        match self.values.get(name)? {
+            ArgValue::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Token {
+    value: String,
+    // Byte offset of the token in the parsed text
+    start: usize,
+    quoted: bool,
+    // The token has a quote that is never closed, kept as a literal "
+    unclosed_quote: bool,
+}
+
+impl Token {
+    // A stray quote is only accepted where the text is taken as typed
+    fn check_quotes(&self) -> Result<()> {
+        if self.unclosed_quote {
+            return Err(eyre!("missing closing quote"));
+        }
+        Ok(())
+    }
+}
+
+// Splits on whitespace, "double quoted strings" are kept together.
+// A quote that is never closed is a literal character of its token.
+fn tokenize(text: &str) -> Vec<Token> {
+    match split_tokens(text, None) {
+        Ok(tokens) => tokens,
+        Err(unclosed) => split_tokens(text, Some(unclosed)).unwrap_or_default(),
+    }
+}
+
+// The token being read, started at this offset when there is none
+fn token_at(current: &mut Option<Token>, start: usize) -> &mut Token {
+    current.get_or_insert_with(|| Token {
+        start,
+        ..Default::default()
+    })
+}
+
+// Returns the offset of the quote left open as the error
+fn split_tokens(text: &str, literal_quote: Option<usize>) -> Result<Vec<Token>, usize> {
+    let mut tokens = Vec::new();
+    let mut current: Option<Token> = None;
+    let mut open_quote = None;
+    for (index, c) in text.char_indices() {
+        match c {
+            '"' if literal_quote == Some(index) => {
+                let token = token_at(&mut current, index);
+                token.unclosed_quote = true;
+                token.value.push(c);
+            }
+            '"' => {
+                open_quote = match open_quote {
+                    Some(_) => None,
+                    None => Some(index),
+                };
+                token_at(&mut current, index).quoted = true;
+            }
+            c if c.is_whitespace() && open_quote.is_none() => tokens.extend(current.take()),
+            c => token_at(&mut current, index).value.push(c),
+        }
+    }
+    if let Some(index) = open_quote {
+        return Err(index);
+    }
+    tokens.extend(current);
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(text: &str) -> Vec<String> {
+        tokenize(text).into_iter().map(|token| token.value).collect()
+    }
+
+    #[test]
+    fn tokenize_keeps_quoted_strings_together() {
+        assert_eq!(values(r#"@Viewer "it-IT Diego"  x"#), vec![
+            "@Viewer",
+            "it-IT Diego",
+            "x"
+        ]);
+        assert_eq!(values(r#""""#), vec![""]);
+        assert!(values("  ").is_empty());
+    }
+
+    #[test]
+    fn tokenize_keeps_an_unclosed_quote_as_typed() {
+        assert_eq!(values(r#"set "it-IT Diego"#), vec!["set", r#""it-IT"#, "Diego"]);
+        assert_eq!(values(r#""a b" c"d e"#), vec!["a b", r#"c"d"#, "e"]);
+    }
+
+    #[test]
+    fn unclosed_quote_is_only_accepted_as_typed_text() {
+        let specs = [
+            ArgSpec::required("user", ArgKind::User),
+            ArgSpec::optional("voice", ArgKind::Word),
+        ];
+        let error = CommandArgs::parse(r#"!lock_voice viewer "it-IT Diego"#, &specs).unwrap_err();
+        assert_eq!(error.to_string(), "missing closing quote");
+
+        let specs = [
+            ArgSpec::required("name", ArgKind::Word),
+            ArgSpec::required("reply", ArgKind::Rest),
+        ];
+        let args = CommandArgs::parse(r#"!add quote He said "hi"#, &specs).unwrap();
+        assert_eq!(args.text("name"), Some("quote"));
+        assert_eq!(args.text("reply"), Some(r#"He said "hi"#));
+    }
+
+    #[test]
+    fn parses_positional_arguments() {
+        let specs = [
+            ArgSpec::required("user", ArgKind::User),
+            ArgSpec::optional("count", ArgKind::Integer { min: 1, max: 10 }),
+        ];
+        let args = CommandArgs::parse("!hug @Viewer 3", &specs).unwrap();
+        assert_eq!(args.text("user"), Some("viewer"));
+        assert_eq!(args.integer("count"), Some(3));
+
+        let args = CommandArgs::parse("!hug viewer", &specs).unwrap();
+        assert_eq!(args.integer("count"), None);
+    }
+
+    #[test]
+    fn reports_invalid_arguments() {
+        let specs = [
+            ArgSpec::required("user", ArgKind::User),
+            ArgSpec::optional("count", ArgKind::Integer { min: 1, max: 10 }),
+        ];
+        let error = |payload| CommandArgs::parse(payload, &specs).unwrap_err().to_string();
+        assert_eq!(error("!hug"), "missing user");
+        assert_eq!(error("!hug viewer 11"), "count must be a whole number from 1 to 10");
+        assert_eq!(error("!hug viewer 1 2"), "unexpected argument 2");
+        assert_eq!(error("!hug a-b"), "a-b is not a user name");
+    }
+
+    #[test]
+    fn rest_keeps_the_text_as_typed() {
+        let specs = [
+            ArgSpec::optional("query", ArgKind::Rest),
+            ArgSpec::named("page", ArgKind::Integer { min: 1, max: 999 }),
+        ];
+        let args = CommandArgs::parse(r#"!voices  it "female  voice" "#, &specs).unwrap();
+        assert_eq!(args.text("query"), Some(r#"it "female  voice""#));
+
+        let args = CommandArgs::parse("!voices page=2 it female", &specs).unwrap();
+        assert_eq!(args.text("query"), Some("it female"));
+        assert_eq!(args.integer("page"), Some(2));
+    }
+
+    #[test]
+    fn parses_numbers_and_choices() {
+        let specs = [
+            ArgSpec::required("dB", ArgKind::Number { min: -60.0, max: 20.0 }),
+            ArgSpec::optional("state", ArgKind::Choice(&["on", "off"])),
+        ];
+        let args = CommandArgs::parse("!volume -10.5 ON", &specs).unwrap();
+        assert_eq!(args.number("dB"), Some(-10.5));
+        assert_eq!(args.text("state"), Some("on"));
+        let error = CommandArgs::parse("!volume 0 maybe", &specs).unwrap_err().to_string();
+        assert_eq!(error, "state must be on or off");
+        assert_eq!(usage(&specs), "<dB> [on|off]");
+    }
+
+    #[test]
+    fn parse_without_specs_keeps_the_tokens() {
+        let args = CommandArgs::parse("!meow a \"b c\"", &[]).unwrap();
+        assert_eq!(args.tokens(), ["a", "b c"]);
+        let args = CommandArgs::parse("!meow \"b c", &[]).unwrap();
+        assert_eq!(args.tokens(), ["\"b", "c"]);
+    }
+}
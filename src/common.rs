@@ -3,7 +3,7 @@ use std::fmt::Debug;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use eyre::Result;
+use eyre::{Result, eyre};
 use serde::{Deserialize, Serialize};
 use tokio::fs::{self, create_dir_all, metadata};
 use tokio::sync::RwLock;
@@ -11,18 +11,30 @@ use tokio::sync::RwLock;
 use crate::*;
 
 pub(crate) trait PersistentConfig {
+    fn file_name() -> String
+    where
+        Self: Sized,
+    {
+        std::any::type_name::<Self>().split("::").last().unwrap().to_owned() + ".toml"
+    }
+
+    fn config_path(config_dir: Option<&str>) -> PathBuf
+    where
+        Self: Sized,
+    {
+        let current_dir = std::env::current_dir().unwrap();
+        match config_dir {
+            Some(config_dir) => current_dir.join(config_dir).join(Self::file_name()),
+            None => current_dir.join(Self::file_name()),
+        }
+    }
+
     async fn save<'a>(&self, config_dir: Option<&'a str>)
     where
         Self: Default + Serialize + for<'de> Deserialize<'de>,
     {
-        let file_name = std::any::type_name::<Self>().split("::").last().unwrap().to_owned() + ".toml";
-        let file_path = if config_dir.is_some() {
-            PathBuf::from(std::env::current_dir().unwrap())
-                .join(config_dir.unwrap())
-                .join(&file_name)
-        } else {
-            PathBuf::from(std::env::current_dir().unwrap()).join(&file_name)
-        };
+        let file_name = Self::file_name();
+        let file_path = Self::config_path(config_dir);
 
         match self.check_file_path(&file_path).await {
             Ok(_) => log!("{} Config path checked successfully", &file_name),
@@ -64,18 +76,24 @@ pub(crate) trait PersistentConfig {
         Ok(())
     }
 
+    // Like load, but a missing or invalid file is an error instead of the defaults
+    async fn try_load(config_dir: Option<&str>) -> Result<Self>
+    where
+        Self: Sized + for<'de> Deserialize<'de>,
+    {
+        let file_path = Self::config_path(config_dir);
+        let content = fs::read_to_string(&file_path)
+            .await
+            .map_err(|e| eyre!("unable to read {}: {}", file_path.display(), e))?;
+        toml::from_str(&content).map_err(|e| eyre!("unable to parse {}: {}", file_path.display(), e.message()))
+    }
+
     async fn load(config_dir: Option<&str>) -> Self
     where
         Self: Default + Serialize + for<'de> Deserialize<'de>,
     {
-        let file_name = std::any::type_name::<Self>().split("::").last().unwrap().to_owned() + ".toml";
-        let file_path = if config_dir.is_some() {
-            PathBuf::from(std::env::current_dir().unwrap())
-                .join(config_dir.unwrap())
-                .join(&file_name)
-        } else {
-            PathBuf::from(std::env::current_dir().unwrap()).join(&file_name)
-        };
+        let file_name = Self::file_name();
+        let file_path = Self::config_path(config_dir);
 
         match fs::read_to_string(&file_path).await {
             Ok(content) => {
@@ -161,6 +179,25 @@ where
         self.notify.notify_waiters();
     }
 
+    pub async fn push_front(&self, payload: T) {
+        self.queue.write().await.push_front(payload);
+        self.notify.notify_waiters();
+    }
+
+    // Inserts the message before the first queued one matching the predicate, at the back when none matches
+    pub async fn insert_before(&self, payload: T, predicate: impl Fn(&T) -> bool) {
+        let mut queue = self.queue.write().await;
+        let index = queue.iter().position(predicate).unwrap_or(queue.len());
+        queue.insert(index, payload);
+        drop(queue);
+        self.notify.notify_waiters();
+    }
+
+    // Removes every queued message, returns how many were removed
+    pub async fn clear(&self) -> usize {
+        self.queue.write().await.drain(..).count()
+    }
+
     pub async fn next(&self) -> Option<T> {
         loop {
             if let Some(value) = self.queue.write().await.pop_front() {
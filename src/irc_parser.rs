@@ -26,6 +26,63 @@ impl IrcMessage {
             payload: payload.into(),
         }
     }
+
+    // Badge names from the badges tag, e.g. "broadcaster/1,subscriber/0" -> ["broadcaster", "subscriber"]
+    pub fn badges(&self) -> Vec<&str> {
+        self.token
+            .get("badges")
+            .map(|badges| {
+                badges
+                    .split(',')
+                    .filter_map(|badge| badge.split('/').next())
+                    .filter(|badge| !badge.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // The display-name tag, or the sender when it's missing
+    pub fn display_name(&self) -> &str {
+        self.token
+            .get("display-name")
+            .map(String::as_str)
+            .filter(|name| !name.is_empty())
+            .unwrap_or(&self.sender)
+    }
+
+    pub fn has_badge(&self, badge: impl AsRef<str>) -> bool {
+        self.badges().contains(&badge.as_ref())
+    }
+
+    fn has_tag(&self, tag: &str) -> bool {
+        self.token.get(tag).is_some_and(|value| value == "1")
+    }
+
+    // Highest role of the sender, from the badges and the mod / vip / subscriber tags
+    pub fn role(&self) -> Role {
+        if self.has_badge("broadcaster") {
+            Role::Broadcaster
+        } else if self.has_tag("mod") || self.has_badge("moderator") {
+            Role::Moderator
+        } else if self.has_tag("vip") || self.has_badge("vip") {
+            Role::Vip
+        } else if self.has_tag("subscriber") || self.has_badge("subscriber") || self.has_badge("founder") {
+            Role::Subscriber
+        } else {
+            Role::Everyone
+        }
+    }
+}
+
+// Chat roles, each one includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Everyone,
+    Subscriber,
+    Vip,
+    Moderator,
+    Broadcaster,
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use futures::executor::block_on;
+use msedge_tts::tts::SpeechConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::CONFIG_DIR;
+use crate::common::PersistentConfig;
+use crate::tts::{TTS_VOCE_BD, VoiceQuery};
+use crate::users::{USER_DEFAULT_VOICE_CONFIG, User};
+
+pub static LANGUAGE_DETECTION: LazyLock<LanguageDetectionConfig> =
+    LazyLock::new(|| LanguageDetectionConfig::init(CONFIG_DIR));
+
+// whatlang reports ISO 639-3 codes, Edge locales use ISO 639-1 (or 639-3 when no 2 letter code exists)
+static ISO_639_3_TO_1: &[(&str, &str)] = &[
+    ("afr", "af"),
+    ("aka", "ak"),
+    ("amh", "am"),
+    ("ara", "ar"),
+    ("aze", "az"),
+    ("bel", "be"),
+    ("ben", "bn"),
+    ("bul", "bg"),
+    ("cat", "ca"),
+    ("ces", "cs"),
+    ("cmn", "zh"),
+    ("dan", "da"),
+    ("deu", "de"),
+    ("ell", "el"),
+    ("eng", "en"),
+    ("epo", "eo"),
+    ("est", "et"),
+    ("fin", "fi"),
+    ("fra", "fr"),
+    ("guj", "gu"),
+    ("heb", "he"),
+    ("hin", "hi"),
+    ("hrv", "hr"),
+    ("hun", "hu"),
+    ("hye", "hy"),
+    ("ind", "id"),
+    ("ita", "it"),
+    ("jav", "jv"),
+    ("jpn", "ja"),
+    ("kan", "kn"),
+    ("kat", "ka"),
+    ("khm", "km"),
+    ("kor", "ko"),
+    ("lat", "la"),
+    ("lav", "lv"),
+    ("lit", "lt"),
+    ("mal", "ml"),
+    ("mar", "mr"),
+    ("mkd", "mk"),
+    ("mya", "my"),
+    ("nep", "ne"),
+    ("nld", "nl"),
+    ("nob", "nb"),
+    ("ori", "or"),
+    ("pan", "pa"),
+    ("pes", "fa"),
+    ("pol", "pl"),
+    ("por", "pt"),
+    ("ron", "ro"),
+    ("rus", "ru"),
+    ("sin", "si"),
+    ("slk", "sk"),
+    ("slv", "sl"),
+    ("sna", "sn"),
+    ("spa", "es"),
+    ("srp", "sr"),
+    ("swe", "sv"),
+    ("tam", "ta"),
+    ("tel", "te"),
+    ("tgl", "fil"),
+    ("tha", "th"),
+    ("tuk", "tk"),
+    ("tur", "tr"),
+    ("ukr", "uk"),
+    ("urd", "ur"),
+    ("uzb", "uz"),
+    ("vie", "vi"),
+    ("yid", "yi"),
+    ("zul", "zu"),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LanguageDetectionConfig {
+    enabled: bool,
+    // Detection confidence between 0.0 and 1.0 required to switch voice
+    min_confidence: f64,
+    // Short messages are detected unreliably, so they are left alone
+    min_text_length: usize,
+    // Multilingual voices already read most languages correctly
+    skip_multilingual_voices: bool,
+    // Language code -> voice short name, e.g. "en" = "en-US-AvaNeural"
+    preferred_voices: HashMap<String, String>,
+}
+
+impl Default for LanguageDetectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_confidence: 0.8,
+            min_text_length: 20,
+            skip_multilingual_voices: true,
+            preferred_voices: HashMap::new(),
+        }
+    }
+}
+
+impl PersistentConfig for LanguageDetectionConfig {}
+
+impl LanguageDetectionConfig {
+    pub fn init(config_dir: Option<&str>) -> Self {
+        block_on(LanguageDetectionConfig::load(config_dir))
+    }
+
+    pub fn warm_up(&self) {}
+
+    // Returns the language code of the text when it is detected with enough confidence
+    pub fn detect(&self, text: impl AsRef<str>) -> Option<String> {
+        let text = text.as_ref();
+        if text.chars().count() < self.min_text_length {
+            return None;
+        }
+
+        let info = whatlang::detect(text)?;
+        log_trace!(
+            "Detected language {} with confidence {:.2}",
+            info.lang().code(),
+            info.confidence()
+        );
+        if info.confidence() < self.min_confidence {
+            return None;
+        }
+
+        let code = info.lang().code();
+        let code = ISO_639_3_TO_1
+            .iter()
+            .find(|(iso_3, _)| *iso_3 == code)
+            .map(|(_, iso_1)| *iso_1)
+            .unwrap_or(code);
+        Some(code.to_string())
+    }
+
+    // Picks the voice to read the text with, when it is in a different language than the user's voice
+    pub fn speech_config_for(&self, user: &User, text: impl AsRef<str>) -> Option<SpeechConfig> {
+        if !self.enabled || !user.auto_language() {
+            return None;
+        }
+
+        let current = TTS_VOCE_BD.find_by_name(&user.get_speech_config().voice_name)?;
+        let current_short_name = current.short_name.clone().unwrap_or_default();
+        if self.skip_multilingual_voices && current_short_name.to_lowercase().contains("multilingual") {
+            return None;
+        }
+
+        let language = self.detect(text)?;
+        let current_language = current.locale.clone().unwrap_or_default().to_lowercase();
+        if current_language.split('-').next() == Some(language.as_str()) {
+            return None;
+        }
+
+        // Per-user choice first, then the streamer's choice, then the best match in the VoiceDB
+        // A choice denied by the allow/deny lists is skipped
+        let voice = [user.language_voice(&language), self.preferred_voices.get(&language)]
+            .into_iter()
+            .flatten()
+            .filter_map(|name| TTS_VOCE_BD.find_by_name(name))
+            .find(|voice| USER_DEFAULT_VOICE_CONFIG.is_voice_allowed(voice))
+            .or_else(|| {
+                // Prefer a voice with the same gender as the current one
+                let same_gender = VoiceQuery {
+                    language: Some(language.clone()),
+                    gender: current.gender.clone().map(|gender| gender.to_lowercase()),
+                    ..Default::default()
+                };
+                let any_gender = VoiceQuery {
+                    gender: None,
+                    ..same_gender.clone()
+                };
+                [same_gender, any_gender]
+                    .iter()
+                    .find_map(|query| TTS_VOCE_BD.search_allowed(query).into_iter().next())
+            })?;
+
+        log_debug!("Switching voice to {} for detected language {}", voice.name, language);
+        Some(SpeechConfig {
+            voice_name: voice.name.clone(),
+            ..user.get_speech_config().clone()
+        })
+    }
+}
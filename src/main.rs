@@ -3,15 +3,23 @@ use task_manager::TASKS_MANAGER;
 pub mod common;
 #[macro_use]
 pub mod macros;
+pub mod audio_decoder;
 pub mod audio_player;
+pub mod audio_sink;
 pub mod bot_commands;
 pub mod bot_external_commands;
+pub mod command_args;
 pub mod irc_parser;
+pub mod language_detection;
+pub mod sound_library;
 pub mod task_manager;
 pub mod task_stats;
+pub mod templates;
+pub mod timers;
 pub mod tts;
 pub mod twitch_client;
 pub mod users;
+pub mod variables;
 
 pub static CONFIG_DIR: Option<&'static str> = Some(".config");
 
@@ -33,6 +41,14 @@ async fn main() -> Result<()> {
         .add("BOT_COMMANDS", || Box::pin(bot_commands::start()), 3)
         .await;
 
+    // Reload the external commands when their file changes
+    TASKS_MANAGER
+        .add("EXT_COMMANDS_WATCHER", || Box::pin(bot_external_commands::watch()), 3)
+        .await;
+
+    // Post the timed messages
+    TASKS_MANAGER.add("TIMERS", || Box::pin(timers::start()), 3).await;
+
     // List all tasks
     TASKS_MANAGER.list().await;
     // Start all tasks
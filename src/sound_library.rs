@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use curl::easy::Easy;
+use eyre::{Result, eyre};
+use futures::executor::block_on;
+use rand::seq::IndexedRandom;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::CONFIG_DIR;
+use crate::audio_decoder::AudioFormat;
+use crate::audio_player::{AUDIO_CONTROL, AudioClip, AudioSource};
+use crate::common::PersistentConfig;
+
+pub static SOUND_LIBRARY: LazyLock<SoundLibrary> = LazyLock::new(|| SoundLibrary::init(CONFIG_DIR));
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SoundLibraryConfig {
+    // Named sounds are looked up in this directory, by file name with or without extension
+    directory: String,
+    // Remote sounds are downloaded once into this directory
+    cache_directory: String,
+    // Decodes every command sound at startup, so broken ones are reported before they are used
+    preload: bool,
+    // Memory kept for decoded sounds, the least recently played ones are dropped first
+    cache_mb: u32,
+}
+
+impl Default for SoundLibraryConfig {
+    fn default() -> Self {
+        Self {
+            directory: "assets/audio".into(),
+            cache_directory: ".cache/sounds".into(),
+            preload: true,
+            cache_mb: 64,
+        }
+    }
+}
+
+impl PersistentConfig for SoundLibraryConfig {}
+
+// Decoded sounds by reference, bounded by their size in memory
+struct ClipCache {
+    max_bytes: usize,
+    used_bytes: usize,
+    // Bumped on every use, the entry with the lowest value is the least recently used
+    uses: u64,
+    clips: HashMap<String, (AudioClip, u64)>,
+}
+
+impl ClipCache {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            used_bytes: 0,
+            uses: 0,
+            clips: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, reference: &str) -> Option<AudioClip> {
+        self.uses += 1;
+        let (clip, last_use) = self.clips.get_mut(reference)?;
+        *last_use = self.uses;
+        Some(clip.clone())
+    }
+
+    fn insert(&mut self, reference: &str, clip: AudioClip) {
+        let size = clip_bytes(&clip);
+        // A sound larger than the whole cache is decoded on every use
+        if size > self.max_bytes {
+            return;
+        }
+        self.uses += 1;
+        if let Some((old, _)) = self.clips.insert(reference.to_string(), (clip, self.uses)) {
+            self.used_bytes -= clip_bytes(&old);
+        }
+        self.used_bytes += size;
+        while self.used_bytes > self.max_bytes {
+            let Some(oldest) = self
+                .clips
+                .iter()
+                .min_by_key(|(_, (_, last_use))| *last_use)
+                .map(|(reference, _)| reference.clone())
+            else {
+                break;
+            };
+            if let Some((clip, _)) = self.clips.remove(&oldest) {
+                self.used_bytes -= clip_bytes(&clip);
+            }
+        }
+    }
+}
+
+fn clip_bytes(clip: &AudioClip) -> usize {
+    std::mem::size_of_val(&*clip.samples)
+}
+
+pub struct SoundLibrary {
+    config: SoundLibraryConfig,
+    clips: Mutex<ClipCache>,
+}
+
+impl SoundLibrary {
+    pub fn init(config_dir: Option<&str>) -> Self {
+        let config = block_on(SoundLibraryConfig::load(config_dir));
+        let max_bytes = config.cache_mb as usize * 1_000_000;
+        Self {
+            config,
+            clips: Mutex::new(ClipCache::new(max_bytes)),
+        }
+    }
+
+    // Decoded sound of a reference: a remote URL, or a name or path in the library directory
+    pub async fn clip(&self, reference: &str, source: AudioSource) -> Result<AudioClip> {
+        if let Some(clip) = self.clips.lock().await.get(reference) {
+            return Ok(AudioClip { source, ..clip });
+        }
+
+        let clip = if reference.starts_with("http://") || reference.starts_with("https://") {
+            self.remote_clip(reference, source).await?
+        } else {
+            // The same error for a missing file and one outside the library, so chat can't probe the disk
+            let path = self
+                .find_in_library(reference)
+                .ok_or_else(|| eyre!("sound {} not found in {}", reference, self.config.directory))?;
+            let data = tokio::fs::read(&path)
+                .await
+                .map_err(|e| eyre!("unable to read {}: {}", path.display(), e))?;
+            AudioClip::decode(data, source).await?
+        };
+        self.clips.lock().await.insert(reference, clip.clone());
+        Ok(clip)
+    }
+
+    // Picks one of the references at random
+    pub async fn random_clip(&self, references: &[String], source: AudioSource) -> Result<AudioClip> {
+        let reference = references
+            .choose(&mut rand::rng())
+            .ok_or_else(|| eyre!("no sound to play"))?;
+        self.clip(reference, source).await
+    }
+
+    // Decodes the references ahead of their first use and logs the ones that can't be played.
+    // Only the most recent ones stay in memory when they don't fit in cache_mb.
+    pub async fn preload(&self, references: impl IntoIterator<Item = String>) {
+        if !self.config.preload {
+            return;
+        }
+        let mut loaded = 0;
+        for reference in references {
+            match self.clip(&reference, AudioSource::CommandSound).await {
+                Ok(_) => loaded += 1,
+                Err(e) => log_error!("Sound {} can't be played: {}", reference, e),
+            }
+        }
+        log!("Preloaded {} sounds", loaded);
+    }
+
+    // Only files inside the library directory, absolute paths, .. and symlinks leading out of it are refused
+    fn find_in_library(&self, name: &str) -> Option<PathBuf> {
+        let directory = Path::new(&self.config.directory).canonicalize().ok()?;
+        let inside = |path: &Path| {
+            path.canonicalize()
+                .ok()
+                .filter(|path| path.starts_with(&directory) && path.is_file())
+        };
+        if let Some(exact) = inside(&directory.join(name)) {
+            return Some(exact);
+        }
+        std::fs::read_dir(&directory)
+            .ok()?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.file_stem().is_some_and(|stem| stem == name))
+            .find_map(|path| inside(&path))
+    }
+
+    // Downloads the URL once, later uses read the cached file.
+    // A cached file that doesn't decode anymore is deleted and downloaded again.
+    async fn remote_clip(&self, url: &str, source: AudioSource) -> Result<AudioClip> {
+        let path = Path::new(&self.config.cache_directory).join(cache_file_name(url));
+        if let Ok(data) = tokio::fs::read(&path).await {
+            match AudioClip::decode(data, source).await {
+                Ok(clip) => return Ok(clip),
+                Err(e) => {
+                    log_warning!(
+                        "Cached sound {} can't be decoded, downloading it again: {}",
+                        path.display(),
+                        e
+                    );
+                    let _ = tokio::fs::remove_file(&path).await;
+                }
+            }
+        }
+
+        log!("Downloading sound {}", url);
+        let max_bytes = AUDIO_CONTROL.read().await.decode_limits().max_bytes;
+        let owned_url = url.to_string();
+        let data = tokio::task::spawn_blocking(move || fetch(&owned_url, max_bytes)).await??;
+        // An error page or a truncated download is never cached
+        if AudioFormat::sniff(&data) == AudioFormat::Unknown {
+            return Err(eyre!("the download is not an audio file"));
+        }
+        let clip = AudioClip::decode(data.clone(), source).await?;
+        if let Err(e) = self.write_cache(&path, &data).await {
+            log_warning!("Unable to cache sound {}: {}", url, e);
+        }
+        Ok(clip)
+    }
+
+    // Written under a temporary name and renamed, so a cached file is always complete
+    async fn write_cache(&self, path: &Path, data: &[u8]) -> Result<()> {
+        tokio::fs::create_dir_all(&self.config.cache_directory).await?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let partial = path.with_file_name(format!("{}.part", file_name));
+        tokio::fs::write(&partial, data).await?;
+        if let Err(e) = tokio::fs::rename(&partial, path).await {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+// Readable and unique file name for a URL, e.g. www.myinstants.com_media_sounds_m-e-o-w.mp3
+fn cache_file_name(url: &str) -> String {
+    url.split_once("://")
+        .map_or(url, |(_, rest)| rest)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || ".-_".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn fetch(url: &str, max_bytes: usize) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut easy = Easy::new();
+    easy.url(url)?;
+    easy.follow_location(true)?;
+    {
+        let mut transfer = easy.transfer();
+        transfer.write_function(|new_data| {
+            // Returning less than received aborts the transfer
+            if data.len() + new_data.len() > max_bytes {
+                return Ok(0);
+            }
+            data.extend_from_slice(new_data);
+            Ok(new_data.len())
+        })?;
+        transfer
+            .perform()
+            .map_err(|e| eyre!("download of {} failed: {}", url, e))?;
+    }
+    match easy.response_code()? {
+        200..=299 => Ok(data),
+        code => Err(eyre!("download of {} failed with HTTP {}", url, code)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4 bytes per sample
+    fn clip(samples: usize) -> AudioClip {
+        AudioClip {
+            samples: vec![0.0; samples].into(),
+            source: AudioSource::CommandSound,
+            priority: 0,
+        }
+    }
+
+    fn library(name: &str) -> (SoundLibrary, PathBuf) {
+        let root = std::env::temp_dir().join(format!("bottarga-library-{}-{}", name, std::process::id()));
+        let directory = root.join("audio");
+        std::fs::create_dir_all(directory.join("drums")).unwrap();
+        std::fs::write(directory.join("laugh.ogg"), b"sound").unwrap();
+        std::fs::write(directory.join("drums").join("rimshot.wav"), b"sound").unwrap();
+        std::fs::write(root.join("secret.txt"), b"secret").unwrap();
+        let library = SoundLibrary {
+            config: SoundLibraryConfig {
+                directory: directory.to_string_lossy().into(),
+                ..Default::default()
+            },
+            clips: Mutex::new(ClipCache::new(100)),
+        };
+        (library, root)
+    }
+
+    #[test]
+    fn finds_sounds_inside_the_library_only() {
+        let (library, root) = library("paths");
+        assert!(library.find_in_library("laugh").is_some());
+        assert!(library.find_in_library("laugh.ogg").is_some());
+        assert!(library.find_in_library("drums/rimshot.wav").is_some());
+        assert!(library.find_in_library("missing").is_none());
+        assert!(library.find_in_library("../secret.txt").is_none());
+        assert!(library.find_in_library("drums/../../secret.txt").is_none());
+        let absolute = root.join("secret.txt");
+        assert!(library.find_in_library(&absolute.to_string_lossy()).is_none());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn cache_files_are_renamed_into_place() {
+        let (mut library, root) = library("cache");
+        library.config.cache_directory = root.join("cache").to_string_lossy().into();
+        let path = root
+            .join("cache")
+            .join(cache_file_name("https://example.com/laugh.mp3"));
+        library.write_cache(&path, b"sound").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"sound");
+        assert!(!path.with_file_name("example.com_laugh.mp3.part").exists());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn drops_the_least_recently_used_clips() {
+        let mut cache = ClipCache::new(100);
+        cache.insert("a", clip(10));
+        cache.insert("b", clip(10));
+        assert!(cache.get("a").is_some());
+        cache.insert("c", clip(10));
+        assert_eq!(cache.used_bytes, 80);
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+    }
+
+    #[test]
+    fn skips_clips_larger_than_the_cache() {
+        let mut cache = ClipCache::new(100);
+        cache.insert("a", clip(10));
+        cache.insert("huge", clip(30));
+        assert!(cache.get("huge").is_none());
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.used_bytes, 40);
+    }
+
+    #[test]
+    fn replacing_a_clip_keeps_the_size_right() {
+        let mut cache = ClipCache::new(100);
+        cache.insert("a", clip(10));
+        cache.insert("a", clip(5));
+        assert_eq!(cache.used_bytes, 20);
+    }
+}
@@ -0,0 +1,468 @@
+use std::sync::LazyLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use eyre::{Result, eyre};
+use rand::seq::IndexedRandom;
+
+use crate::command_args::CommandArgs;
+use crate::irc_parser::{IrcMessage, Role};
+use crate::variables::{self, VARIABLES};
+
+// Start of the bot, for {UPTIME}
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+pub fn warm_up() {
+    LazyLock::force(&STARTED);
+}
+
+// A value of a reply, written {NAME}, {NAME:parameter} or {NAME?fallback}
+#[derive(Debug, Clone, PartialEq)]
+enum Variable {
+    // {SENDER}
+    Sender,
+    // {DISPLAY_NAME}
+    DisplayName,
+    // {CHANNEL}
+    Channel,
+    // {ARG} or {ARGS}, all the arguments
+    Args,
+    // {ARG1}, {ARG2}, ...
+    Arg(usize),
+    // {ARG2+}, the arguments from the second one
+    ArgsFrom(usize),
+    // {TARGET}, the user in the first argument, or the sender
+    Target,
+    // {RANDOM:a|b|c}
+    Random(Vec<String>),
+    // {COUNT}, runs of the command, stored as the count_<command> variable
+    Count,
+    // {VAR:name}, a stored variable
+    Stored(String),
+    // {VAR+:name} or {VAR-:name}, adds 1 to a stored counter or subtracts 1, and shows the new value
+    Adjust(String, i64),
+    // {UPTIME}
+    Uptime,
+    // {TIME}, UTC
+    Time,
+}
+
+impl Variable {
+    fn parse(name: &str, parameter: Option<&str>) -> Result<Self> {
+        let upper = name.trim().to_uppercase();
+        let variable = match upper.as_str() {
+            "SENDER" | "USER" => Variable::Sender,
+            "DISPLAY_NAME" => Variable::DisplayName,
+            "CHANNEL" => Variable::Channel,
+            "ARG" | "ARGS" => Variable::Args,
+            "TARGET" => Variable::Target,
+            "COUNT" => Variable::Count,
+            "UPTIME" => Variable::Uptime,
+            "TIME" => Variable::Time,
+            "RANDOM" => {
+                let choices = parameter
+                    .map(|choices| choices.split('|').map(str::to_string).collect::<Vec<_>>())
+                    .filter(|choices| choices.len() > 1)
+                    .ok_or_else(|| eyre!("{{RANDOM}} needs choices, e.g. {{RANDOM:heads|tails}}"))?;
+                return Ok(Variable::Random(choices));
+            }
+            "VAR" | "VAR+" | "VAR-" => {
+                let name = parameter.ok_or_else(|| eyre!("{{{}}} needs a name, e.g. {{{}:deaths}}", upper, upper))?;
+                let name = variables::checked_name(name)?;
+                return Ok(match upper.as_str() {
+                    "VAR+" => Variable::Adjust(name, 1),
+                    "VAR-" => Variable::Adjust(name, -1),
+                    _ => Variable::Stored(name),
+                });
+            }
+            _ => {
+                let index = upper
+                    .strip_prefix("ARG")
+                    .ok_or_else(|| eyre!("unknown variable {{{}}}", name))?;
+                let (index, from) = match index.strip_suffix('+') {
+                    Some(index) => (index, true),
+                    None => (index, false),
+                };
+                let index = index
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|index| *index > 0)
+                    .ok_or_else(|| eyre!("unknown variable {{{}}}", name))?;
+                if from {
+                    Variable::ArgsFrom(index)
+                } else {
+                    Variable::Arg(index)
+                }
+            }
+        };
+        if parameter.is_some() {
+            return Err(eyre!("{{{}}} takes no parameter", name));
+        }
+        Ok(variable)
+    }
+
+    async fn value(&self, context: &TemplateContext<'_>) -> String {
+        let tokens = context.args.tokens();
+        match self {
+            Variable::Sender => context.message.sender.clone(),
+            Variable::DisplayName => context.message.display_name().to_string(),
+            Variable::Channel => context.message.destination.trim_start_matches('#').to_string(),
+            Variable::Args => context
+                .args
+                .text("text")
+                .map(str::to_string)
+                .unwrap_or_else(|| tokens.join(" ")),
+            Variable::Arg(index) => tokens.get(index - 1).cloned().unwrap_or_default(),
+            Variable::ArgsFrom(index) => tokens.get(index - 1..).unwrap_or_default().join(" "),
+            Variable::Target => tokens
+                .first()
+                .map(|target| target.trim_start_matches('@').to_string())
+                .filter(|target| !target.is_empty())
+                .unwrap_or_else(|| context.message.sender.clone()),
+            Variable::Random(choices) => choices.choose(&mut rand::rng()).cloned().unwrap_or_default(),
+            Variable::Count => change_counter(context, &count_variable(context.command), 1).await,
+            Variable::Stored(name) => stored_value(name).await,
+            Variable::Adjust(name, amount) => change_counter(context, name, *amount).await,
+            Variable::Uptime => format_duration(STARTED.elapsed().as_secs()),
+            Variable::Time => {
+                let secs = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                format!("{:02}:{:02} UTC", secs / 3600 % 24, secs / 60 % 60)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Variable {
+        variable: Variable,
+        // Used when the value is empty, e.g. a missing argument
+        fallback: Option<String>,
+    },
+}
+
+// What a reply can refer to
+pub struct TemplateContext<'a> {
+    pub message: &'a IrcMessage,
+    pub args: &'a CommandArgs,
+    // Trigger of the command, for {COUNT}
+    pub command: &'a str,
+}
+
+// A parsed reply. {{ and }} are literal braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut inner = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some('{') | None => return Err(eyre!("unclosed {{ in \"{}\"", text)),
+                            Some(c) => inner.push(c),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut literal)));
+                    }
+                    segments.push(parse_placeholder(&inner)?);
+                }
+                '}' => return Err(eyre!("unmatched }} in \"{}\", write }}}} for a brace", text)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Text(literal));
+        }
+        Ok(Self { segments })
+    }
+
+    // Template of a text used as it is, for replies that don't parse
+    pub fn text(text: &str) -> Self {
+        Self {
+            segments: vec![Segment::Text(text.to_string())],
+        }
+    }
+
+    // Whether the reply needs arguments, the ones with a fallback are optional
+    pub fn requires_args(&self) -> bool {
+        self.segments.iter().any(|segment| {
+            matches!(segment, Segment::Variable {
+                variable: Variable::Args | Variable::Arg(_) | Variable::ArgsFrom(_),
+                fallback: None,
+            })
+        })
+    }
+
+    pub async fn render(&self, context: &TemplateContext<'_>) -> String {
+        let mut rendered = String::new();
+        // A run is counted once, however many times the reply shows {COUNT}
+        let mut count = None;
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => rendered.push_str(text),
+                Segment::Variable { variable, fallback } => {
+                    let value = match variable {
+                        Variable::Count if count.is_some() => count.clone().unwrap_or_default(),
+                        Variable::Count => count.insert(variable.value(context).await).clone(),
+                        _ => variable.value(context).await,
+                    };
+                    match fallback {
+                        Some(fallback) if value.is_empty() => rendered.push_str(fallback),
+                        _ => rendered.push_str(&value),
+                    }
+                }
+            }
+        }
+        rendered
+    }
+}
+
+// NAME, NAME:parameter or NAME?fallback. A parameter is taken whole, so {RANDOM:really?|no} has no fallback,
+// except a variable name, which can't have a ?: {VAR:deaths?0}
+fn parse_placeholder(inner: &str) -> Result<Segment> {
+    let (name, rest) = inner.split_at(inner.find([':', '?']).unwrap_or(inner.len()));
+    let (parameter, fallback) = match rest.split_at_checked(1) {
+        Some(("?", fallback)) => (None, Some(fallback)),
+        Some((_, parameter)) if matches!(name.trim().to_uppercase().as_str(), "VAR" | "VAR+" | "VAR-") => {
+            match parameter.split_once('?') {
+                Some((parameter, fallback)) => (Some(parameter), Some(fallback)),
+                None => (Some(parameter), None),
+            }
+        }
+        Some((_, parameter)) => (Some(parameter), None),
+        None => (None, None),
+    };
+    Ok(Segment::Variable {
+        variable: Variable::parse(name, parameter)?,
+        fallback: fallback.map(str::to_string),
+    })
+}
+
+// Variable counting the runs of a command, e.g. count_for_president
+pub fn count_variable(command: &str) -> String {
+    let command = command
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    format!("count_{}", command)
+}
+
+// New value of the counter, empty when the variable is a text
+// Stored values are changed by moderators only, like with !var
+fn can_change_variables(message: &IrcMessage) -> bool {
+    message.role() >= Role::Moderator
+}
+
+async fn stored_value(name: &str) -> String {
+    VARIABLES
+        .read()
+        .await
+        .get(name)
+        .map(|value| value.to_string())
+        .unwrap_or_default()
+}
+
+// The new value of the counter, or the unchanged one when the sender can't change it
+async fn change_counter(context: &TemplateContext<'_>, name: &str, amount: i64) -> String {
+    if !can_change_variables(context.message) {
+        return stored_value(name).await;
+    }
+    match VARIABLES.write().await.add(name, amount).await {
+        Ok(value) => value.to_string(),
+        Err(e) => {
+            log_error!("Unable to change the variable {}: {}", name, e);
+            String::new()
+        }
+    }
+}
+
+// e.g. 2h 05m, or 4m 10s under an hour
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..3600 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs / 60 % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc_parser::parse_message;
+
+    fn message() -> IrcMessage {
+        parse_message("@display-name=Viewer :viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #chan :!hug")
+    }
+
+    async fn render(template: &str, command_line: &str) -> String {
+        let message = message();
+        let args = CommandArgs::parse(command_line, &[]).unwrap();
+        let context = TemplateContext {
+            message: &message,
+            args: &args,
+            command: "hug",
+        };
+        Template::parse(template).unwrap().render(&context).await
+    }
+
+    fn parse_error(template: &str) -> String {
+        Template::parse(template).unwrap_err().to_string()
+    }
+
+    #[tokio::test]
+    async fn renders_the_message_values() {
+        assert_eq!(
+            render("{SENDER} {display_name} in {CHANNEL}", "!hug").await,
+            "viewer Viewer in chan"
+        );
+    }
+
+    #[tokio::test]
+    async fn escaped_braces_are_literal() {
+        assert_eq!(render("{{SENDER}} is {SENDER}", "!hug").await, "{SENDER} is viewer");
+        assert_eq!(render("}}{{", "!hug").await, "}{");
+    }
+
+    #[tokio::test]
+    async fn renders_the_arguments() {
+        assert_eq!(render("{ARG}", r#"!hug a "b c" d"#).await, "a b c d");
+        assert_eq!(render("{ARG2}", r#"!hug a "b c" d"#).await, "b c");
+        assert_eq!(render("{ARG2+}", "!hug a b c").await, "b c");
+        assert_eq!(render("{TARGET}", "!hug @Bob").await, "Bob");
+        assert_eq!(render("{TARGET}", "!hug").await, "viewer");
+    }
+
+    #[tokio::test]
+    async fn missing_arguments_are_empty() {
+        assert_eq!(render("[{ARG3}]", "!hug a b").await, "[]");
+        assert_eq!(render("[{ARG3+}]", "!hug a b").await, "[]");
+        assert_eq!(render("[{ARG5+}]", "!hug a b").await, "[]");
+    }
+
+    #[tokio::test]
+    async fn fallback_replaces_an_empty_value() {
+        assert_eq!(render("hugs {ARG1?everyone}!", "!hug").await, "hugs everyone!");
+        assert_eq!(render("hugs {ARG1?everyone}!", "!hug bob").await, "hugs bob!");
+        assert_eq!(render("{ARG2+?nothing}", "!hug a").await, "nothing");
+    }
+
+    #[tokio::test]
+    async fn random_picks_one_of_the_choices() {
+        let rendered = render("{RANDOM:heads|tails}", "!flip").await;
+        assert!(rendered == "heads" || rendered == "tails", "{}", rendered);
+    }
+
+    #[test]
+    fn fallback_only_follows_the_name_or_a_variable_name() {
+        let segments = Template::parse("{RANDOM:really?|no}{ARG1?a:b}{VAR:deaths?0}")
+            .unwrap()
+            .segments;
+        assert_eq!(segments, vec![
+            Segment::Variable {
+                variable: Variable::Random(vec!["really?".into(), "no".into()]),
+                fallback: None,
+            },
+            Segment::Variable {
+                variable: Variable::Arg(1),
+                fallback: Some("a:b".into()),
+            },
+            Segment::Variable {
+                variable: Variable::Stored("deaths".into()),
+                fallback: Some("0".into()),
+            },
+        ]);
+    }
+
+    #[test]
+    fn rejects_unclosed_and_unmatched_braces() {
+        assert!(parse_error("hi {SENDER").starts_with("unclosed {"));
+        assert!(parse_error("hi {SEN{DER}").starts_with("unclosed {"));
+        assert!(parse_error("hi } there").starts_with("unmatched }"));
+    }
+
+    #[test]
+    fn rejects_invalid_variables() {
+        assert_eq!(parse_error("{NAME}"), "unknown variable {NAME}");
+        assert_eq!(parse_error("{ARG0}"), "unknown variable {ARG0}");
+        assert_eq!(parse_error("{ARGx+}"), "unknown variable {ARGx+}");
+        assert_eq!(parse_error("{SENDER:x}"), "{SENDER} takes no parameter");
+        assert!(parse_error("{RANDOM:heads}").starts_with("{RANDOM} needs choices"));
+        assert!(parse_error("{VAR}").starts_with("{VAR} needs a name"));
+        assert_eq!(parse_error("{VAR:two words}"), "two words is not a valid variable name");
+    }
+
+    #[test]
+    fn parses_the_counter_changes() {
+        let segments = Template::parse("{VAR+:Deaths} {var-:deaths}").unwrap().segments;
+        assert_eq!(segments, vec![
+            Segment::Variable {
+                variable: Variable::Adjust("deaths".into(), 1),
+                fallback: None,
+            },
+            Segment::Text(" ".into()),
+            Segment::Variable {
+                variable: Variable::Adjust("deaths".into(), -1),
+                fallback: None,
+            },
+        ]);
+        assert!(parse_error("{VAR+}").starts_with("{VAR+} needs a name"));
+    }
+
+    #[test]
+    fn only_moderators_change_variables() {
+        assert!(!can_change_variables(&message()));
+        let vip = parse_message("@vip=1 :viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #chan :!died");
+        assert!(!can_change_variables(&vip));
+        let moderator = parse_message("@mod=1 :viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #chan :!died");
+        assert!(can_change_variables(&moderator));
+        let broadcaster = parse_message("@badges=broadcaster/1 :chan!chan@chan.tmi.twitch.tv PRIVMSG #chan :!died");
+        assert!(can_change_variables(&broadcaster));
+    }
+
+    #[test]
+    fn count_variable_is_a_valid_name() {
+        assert_eq!(count_variable("for_president"), "count_for_president");
+        assert_eq!(count_variable("Hug-Me"), "count_hug_me");
+        assert!(variables::checked_name(&count_variable("ça-va")).is_ok());
+    }
+
+    #[test]
+    fn arguments_without_fallback_are_required() {
+        assert!(Template::parse("{ARG} for President!").unwrap().requires_args());
+        assert!(Template::parse("{ARG2+}").unwrap().requires_args());
+        assert!(!Template::parse("{ARG1?everyone}").unwrap().requires_args());
+        assert!(!Template::parse("{SENDER} {TARGET}").unwrap().requires_args());
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format_duration(250), "4m 10s");
+        assert_eq!(format_duration(7500), "2h 05m");
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use eyre::{Result, eyre};
+use futures::executor::block_on;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::CONFIG_DIR;
+use crate::bot_commands::{BOT_COMMANDS, CommandSpec};
+use crate::command_args::{ArgKind, ArgSpec, CommandArgs};
+use crate::common::PersistentConfig;
+use crate::irc_parser::{IrcMessage, Role};
+use crate::tts::{TTS_QUEUE, voice_msg};
+use crate::twitch_client::{TWITCH_BOT_INFO, TWITCH_BROADCAST, TWITCH_RECEIVER};
+
+pub static TIMERS: LazyLock<RwLock<Timers>> = LazyLock::new(|| RwLock::new(Timers::init(CONFIG_DIR)));
+
+// How often the timers are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Shorter intervals are raised to this, so a typo can't flood the chat
+const MIN_INTERVAL_SECS: u64 = 60;
+
+const TIMER_USAGE: &str = "list | enable <name> | disable <name>";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimedMessage {
+    message: String,
+    interval_secs: u64,
+    // Chat messages needed since the last post, so the bot doesn't talk to an empty chat
+    #[serde(default)]
+    min_chat_messages: u64,
+    // Channels where the message is posted, every channel when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    channels: Vec<String>,
+    // The message is also read out
+    #[serde(default)]
+    tts: bool,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl TimedMessage {
+    fn targets(&self, channel: &str) -> bool {
+        let channel = channel.trim_start_matches('#');
+        self.channels.is_empty()
+            || self
+                .channels
+                .iter()
+                .any(|target| target.trim_start_matches('#').eq_ignore_ascii_case(channel))
+    }
+}
+
+// Messages posted in chat at regular intervals
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Timers {
+    timers: BTreeMap<String, TimedMessage>,
+}
+
+impl PersistentConfig for Timers {}
+
+impl Default for Timers {
+    fn default() -> Self {
+        let socials = TimedMessage {
+            message: "Follow the channel to know when the next stream starts!".into(),
+            interval_secs: 1800,
+            min_chat_messages: 5,
+            channels: Vec::new(),
+            tts: false,
+            enabled: false,
+        };
+
+        let mut timers = BTreeMap::new();
+        timers.insert("socials".into(), socials);
+        Self { timers }
+    }
+}
+
+impl Timers {
+    pub fn init(config_dir: Option<&str>) -> Timers {
+        let mut timers = block_on(Timers::load(config_dir));
+        timers.normalize();
+        timers
+    }
+
+    // This will be called on bot start to load the timers
+    pub fn warm_up(&self) {}
+
+    // Names are not case sensitive, and every interval is at least MIN_INTERVAL_SECS
+    fn normalize(&mut self) {
+        for (name, mut timer) in std::mem::take(&mut self.timers) {
+            if timer.interval_secs < MIN_INTERVAL_SECS {
+                log_warning!(
+                    "Timer {} posts every {}s, using the minimum of {}s",
+                    name,
+                    timer.interval_secs,
+                    MIN_INTERVAL_SECS
+                );
+                timer.interval_secs = MIN_INTERVAL_SECS;
+            }
+            let key = name.to_lowercase();
+            if self.timers.contains_key(&key) {
+                log_warning!("Timer {} is defined more than once, ignoring {}", key, name);
+                continue;
+            }
+            self.timers.insert(key, timer);
+        }
+    }
+
+    async fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<()> {
+        let timer = self
+            .timers
+            .get_mut(&name.to_lowercase())
+            .ok_or_else(|| eyre!("{} is not a timer", name))?;
+        timer.enabled = enabled;
+        self.save(CONFIG_DIR).await;
+        Ok(())
+    }
+}
+
+// Runtime state of a timer, reset when it is disabled
+struct TimerState {
+    last_post: Instant,
+    chat_messages: u64,
+}
+
+impl TimerState {
+    fn new() -> Self {
+        Self {
+            last_post: Instant::now(),
+            chat_messages: 0,
+        }
+    }
+}
+
+pub async fn start() -> Result<()> {
+    TIMERS.read().await.warm_up();
+    BOT_COMMANDS
+        .register(
+            "timer",
+            CommandSpec {
+                permission: Role::Moderator.into(),
+                description: "Lists, enables or disables the timed messages".into(),
+                args: vec![
+                    ArgSpec::required("action", ArgKind::Word),
+                    ArgSpec::optional("name", ArgKind::Word),
+                ],
+                usage: TIMER_USAGE.into(),
+                category: "timers".into(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_timer(irc_message, args))),
+        )
+        .await;
+    BOT_COMMANDS.builtins_registered("timers").await;
+
+    let mut chat_rx = TWITCH_BROADCAST.subscribe_broadcast().await;
+    let mut check_interval = tokio::time::interval(CHECK_INTERVAL);
+    let mut states: HashMap<String, TimerState> = HashMap::new();
+    loop {
+        tokio::select! {
+            _ = check_interval.tick() => post_due_timers(&mut states).await,
+            message = chat_rx.recv() => match message {
+                Ok(message) if message.command == "PRIVMSG" => {
+                    let timers = TIMERS.read().await;
+                    for (name, state) in states.iter_mut() {
+                        if timers.timers.get(name).is_some_and(|timer| timer.targets(&message.destination)) {
+                            state.chat_messages += 1;
+                        }
+                    }
+                }
+                Ok(_) => {}
+                // Missed messages only delay the timers
+                Err(RecvError::Lagged(skipped)) => log_debug!("Timers missed {} chat messages", skipped),
+                Err(RecvError::Closed) => return Err(eyre!("Twitch broadcast channel closed")),
+            },
+        }
+    }
+}
+
+async fn post_due_timers(states: &mut HashMap<String, TimerState>) {
+    let channel = TWITCH_BOT_INFO.channel().await;
+    let timers = TIMERS.read().await;
+    states.retain(|name, _| timers.timers.get(name).is_some_and(|timer| timer.enabled));
+
+    for (name, timer) in timers.timers.iter().filter(|(_, timer)| timer.enabled) {
+        let state = states.entry(name.clone()).or_insert_with(TimerState::new);
+        if !timer.targets(&channel)
+            || state.last_post.elapsed() < Duration::from_secs(timer.interval_secs)
+            || state.chat_messages < timer.min_chat_messages
+        {
+            continue;
+        }
+        log_debug!("Posting timer {}", name);
+        *state = TimerState::new();
+        if timer.tts {
+            TTS_QUEUE
+                .push_back(voice_msg(&timer.message, &TWITCH_BOT_INFO.nick_name().await).await)
+                .await;
+        }
+        TWITCH_RECEIVER.send_privmsg(&timer.message).await;
+    }
+}
+
+// !timer list|enable|disable
+async fn bot_cmd_timer(message: IrcMessage, args: CommandArgs) -> Result<()> {
+    let action = args.text("action").unwrap_or_default();
+    let reply = match (action, args.text("name")) {
+        ("list", None) => Ok(list_timers().await),
+        ("enable", Some(name)) => TIMERS
+            .write()
+            .await
+            .set_enabled(name, true)
+            .await
+            .map(|_| format!("timer {} enabled", name)),
+        ("disable", Some(name)) => TIMERS
+            .write()
+            .await
+            .set_enabled(name, false)
+            .await
+            .map(|_| format!("timer {} disabled", name)),
+        _ => Err(eyre!("usage: {}", TIMER_USAGE)),
+    };
+    let reply = reply.unwrap_or_else(|e| e.to_string());
+    TWITCH_RECEIVER
+        .send_privmsg(format!("@{}, {}", message.sender, reply))
+        .await;
+    Ok(())
+}
+
+async fn list_timers() -> String {
+    let timers = TIMERS.read().await;
+    if timers.timers.is_empty() {
+        return "there are no timers".into();
+    }
+    let list = timers
+        .timers
+        .iter()
+        .map(|(name, timer)| {
+            let status = if timer.enabled { "on" } else { "off" };
+            format!("{} (every {}m, {})", name, timer.interval_secs.div_ceil(60), status)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("timers: {}", list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timer(interval_secs: u64, channels: &[&str]) -> TimedMessage {
+        TimedMessage {
+            message: "Stay hydrated!".into(),
+            interval_secs,
+            min_chat_messages: 0,
+            channels: channels.iter().map(|channel| channel.to_string()).collect(),
+            tts: false,
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn normalize_lowercases_the_names_and_raises_short_intervals() {
+        let mut timers = Timers {
+            timers: BTreeMap::from([
+                ("Hydrate".to_string(), timer(0, &[])),
+                ("hydrate".to_string(), timer(900, &[])),
+                ("socials".to_string(), timer(1800, &[])),
+            ]),
+        };
+        timers.normalize();
+        assert_eq!(timers.timers.keys().collect::<Vec<_>>(), ["hydrate", "socials"]);
+        assert_eq!(timers.timers["hydrate"].interval_secs, MIN_INTERVAL_SECS);
+        assert_eq!(timers.timers["socials"].interval_secs, 1800);
+    }
+
+    #[test]
+    fn targets_ignores_the_hash_and_the_case() {
+        assert!(timer(60, &[]).targets("#anyone"));
+        assert!(timer(60, &["#IcsBoyx"]).targets("icsboyx"));
+        assert!(timer(60, &["icsboyx"]).targets("#icsboyx"));
+        assert!(!timer(60, &["icsboyx"]).targets("#someone"));
+    }
+}
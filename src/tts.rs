@@ -1,51 +1,168 @@
 #![allow(dead_code)]
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 
 use eyre::Result;
+use futures::executor::block_on;
 use msedge_tts::tts::SpeechConfig;
 use msedge_tts::voice::{Voice, get_voices_list};
 use rand::Rng;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
 
 use crate::CONFIG_DIR;
-use crate::audio_player::TTS_AUDIO_QUEUE;
-use crate::bot_commands::BOT_COMMANDS;
+use crate::audio_player::{AudioClip, AudioSource, enqueue};
+use crate::bot_commands::{BOT_COMMANDS, BOT_COMMANDS_CONFIG, CommandSpec, Subcommand};
+use crate::command_args::{ArgKind, ArgSpec, CommandArgs};
 use crate::common::{MSGQueue, PersistentConfig};
-use crate::irc_parser::IrcMessage;
+use crate::irc_parser::{IrcMessage, Role};
+use crate::language_detection::LANGUAGE_DETECTION;
 use crate::twitch_client::{TWITCH_BOT_INFO, TWITCH_RECEIVER};
 use crate::users::{USER_DB, USER_DEFAULT_VOICE_CONFIG};
 
 pub static TTS_VOCE_BD: LazyLock<VoiceDB> = LazyLock::new(|| VoiceDB::default());
 pub static TTS_QUEUE: LazyLock<MSGQueue<TTSMassage>> = LazyLock::new(|| MSGQueue::new());
+pub static SPEAKER_ANNOUNCEMENT: LazyLock<SpeakerAnnouncementConfig> =
+    LazyLock::new(|| SpeakerAnnouncementConfig::init(CONFIG_DIR));
+static LAST_SPEAKER: LazyLock<RwLock<Option<(String, Instant)>>> = LazyLock::new(|| RwLock::new(None));
+static VOICES_PAGE_SIZE: usize = 10;
 static TRANSFORM_CHARS: &[(char, &str)] = &[('&', "and"), ('%', "percent")];
+// Requested for every voice, whatever codec it suggests, since the audio decoder has no Opus or WebM support
+const TTS_AUDIO_FORMAT: &str = "audio-24khz-48kbitrate-mono-mp3";
 
 pub async fn start() -> Result<()> {
     // This is calling the warm_up method on the USER_DB, to preload all users
     USER_DB.read().await.warm_up();
     USER_DEFAULT_VOICE_CONFIG.warm_up();
+    LANGUAGE_DETECTION.warm_up();
+    SPEAKER_ANNOUNCEMENT.warm_up();
 
     // This is saving the TTS_VOCE_BD to the CONFIG_DIR, for user consultation
     // Does not have real impact on the code.
     TTS_VOCE_BD.save(CONFIG_DIR).await;
 
-    // Registering the list_locales and reset_voice commands
+    // Registering the voice commands
     BOT_COMMANDS
-        .add_command(
+        .register(
             "list_locales",
-            Arc::new(|irc_message| Box::pin(bot_cmd_tts_list_all_locales(irc_message))),
+            CommandSpec {
+                description: "Lists the locales of the TTS voices".into(),
+                category: "voice".into(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_tts_list_all_locales(irc_message, args))),
         )
         .await;
 
-    // Registering the reset_voice command
     BOT_COMMANDS
-        .add_command(
+        .register(
             "reset_voice",
-            Arc::new(|irc_message| Box::pin(bot_cmd_tts_reset_voice(irc_message))),
+            CommandSpec {
+                description: "Picks a new random voice, optionally matching the filters".into(),
+                args: vec![ArgSpec::optional("filters", ArgKind::Rest)],
+                category: "voice".into(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_tts_reset_voice(irc_message, args))),
+        )
+        .await;
+
+    BOT_COMMANDS
+        .register(
+            "voices",
+            CommandSpec {
+                description: "Searches the TTS voices by name, locale or gender".into(),
+                args: vec![
+                    ArgSpec::optional("query", ArgKind::Rest),
+                    ArgSpec::named("page", ArgKind::Integer { min: 1, max: 999 }),
+                ],
+                category: "voice".into(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_tts_search_voices(irc_message, args))),
+        )
+        .await;
+
+    BOT_COMMANDS
+        .register(
+            "set_voice",
+            CommandSpec {
+                description: "Sets your TTS voice".into(),
+                args: vec![ArgSpec::required("name", ArgKind::Word)],
+                category: "voice".into(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_tts_set_voice(irc_message, args))),
+        )
+        .await;
+
+    BOT_COMMANDS
+        .register(
+            "voice",
+            CommandSpec {
+                description: "Shows or customizes your TTS voice".into(),
+                category: "voice".into(),
+                subcommands: voice_subcommands(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_tts_voice(irc_message, args))),
         )
         .await;
 
+    // Registering the moderator voice commands
+    BOT_COMMANDS
+        .register(
+            "set_user_voice",
+            CommandSpec {
+                permission: Role::Moderator.into(),
+                description: "Sets the TTS voice of a user".into(),
+                args: vec![
+                    ArgSpec::required("user", ArgKind::User),
+                    ArgSpec::required("voice", ArgKind::Word),
+                ],
+                category: "voice".into(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_tts_set_user_voice(irc_message, args))),
+        )
+        .await;
+
+    BOT_COMMANDS
+        .register(
+            "lock_voice",
+            CommandSpec {
+                permission: Role::Moderator.into(),
+                description: "Locks the TTS voice of a user, optionally setting it first".into(),
+                args: vec![
+                    ArgSpec::required("user", ArgKind::User),
+                    ArgSpec::optional("voice", ArgKind::Word),
+                ],
+                category: "voice".into(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_tts_lock_voice(irc_message, args))),
+        )
+        .await;
+
+    BOT_COMMANDS
+        .register(
+            "unlock_voice",
+            CommandSpec {
+                permission: Role::Moderator.into(),
+                description: "Lets a user change their TTS voice again".into(),
+                args: vec![ArgSpec::required("user", ArgKind::User)],
+                category: "voice".into(),
+                ..Default::default()
+            },
+            Arc::new(|irc_message, args| Box::pin(bot_cmd_tts_unlock_voice(irc_message, args))),
+        )
+        .await;
+
+    BOT_COMMANDS.builtins_registered("tts").await;
+
     // This is the main loop for the TTS system, waiting for message.
     while let Some(tts_message) = TTS_QUEUE.next().await {
         text_to_speech(tts_message).await?;
@@ -56,6 +173,9 @@ pub async fn start() -> Result<()> {
 pub struct TTSMassage {
     pub speech_config: SpeechConfig,
     pub payload: String,
+    pub source: AudioSource,
+    // Who the message is read for, told in chat when it can't be played
+    pub nick: String,
 }
 
 impl Default for TTSMassage {
@@ -69,7 +189,72 @@ impl Default for TTSMassage {
                 volume: 0,
             },
             payload: "".into(),
+            source: AudioSource::ChatTts,
+            nick: "".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeakerAnnouncementConfig {
+    enabled: bool,
+    // {DISPLAY_NAME}, {NICK} and {MESSAGE} are replaced before speaking
+    template: String,
+    // The name is not repeated when the same user speaks again within this window, 0 always announces
+    same_speaker_window_secs: u64,
+    // Nick -> how the name should be spoken, for users with unreadable nicks. Nicks are not case sensitive.
+    pronunciations: HashMap<String, String>,
+}
+
+impl Default for SpeakerAnnouncementConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            template: "{DISPLAY_NAME} says: {MESSAGE}".into(),
+            same_speaker_window_secs: 30,
+            pronunciations: HashMap::new(),
+        }
+    }
+}
+
+impl PersistentConfig for SpeakerAnnouncementConfig {}
+
+impl SpeakerAnnouncementConfig {
+    pub fn init(config_dir: Option<&str>) -> Self {
+        block_on(SpeakerAnnouncementConfig::load(config_dir))
+    }
+
+    pub fn warm_up(&self) {}
+
+    // Returns the text to speak for a chat message, with the speaker name when required
+    pub async fn announce(&self, message: &IrcMessage) -> String {
+        let nick = &message.sender;
+        let previous = LAST_SPEAKER.write().await.replace((nick.clone(), Instant::now()));
+
+        if !self.enabled || !USER_DB.write().await.get_user(nick).await.announce_name() {
+            return message.payload.clone();
         }
+
+        let window = Duration::from_secs(self.same_speaker_window_secs);
+        if let Some((last_nick, last_time)) = previous
+            && last_nick == *nick
+            && last_time.elapsed() < window
+        {
+            return message.payload.clone();
+        }
+
+        let display_name = self
+            .pronunciations
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(nick))
+            .map(|(_, spoken)| spoken.clone())
+            .unwrap_or_else(|| message.display_name().to_string());
+
+        self.template
+            .replace("{DISPLAY_NAME}", &display_name)
+            .replace("{NICK}", nick)
+            .replace("{MESSAGE}", &message.payload)
     }
 }
 
@@ -94,17 +279,8 @@ impl VoiceDB {
     }
 
     pub fn filter_voices_by_text(&self, filter: &[&str]) -> Self {
-        let voice_list = self
-            .voice_list
-            .iter()
-            .cloned()
-            .filter(|v| {
-                let v_text = format! {"{:?}", v};
-                filter
-                    .iter()
-                    .all(|f| v_text.to_lowercase().contains(f.to_lowercase().as_str()))
-            })
-            .collect::<Vec<_>>();
+        let query = VoiceQuery::parse(filter);
+        let voice_list = self.search(&query).into_iter().cloned().collect::<Vec<_>>();
 
         if voice_list.is_empty() {
             log_debug!(
@@ -116,63 +292,65 @@ impl VoiceDB {
         Self { voice_list }
     }
 
-    pub async fn list_all_locales(&self) -> Vec<String> {
-        let mut locales = HashSet::new();
-
-        // Collect unique locales into the HashSet
-        for voice in &self.voice_list {
-            if let Some(locale) = &voice.locale {
-                locales.insert(locale.clone()); // Insert clones to avoid ownership issues
-            }
-        }
+    // Returns all voices matching the query, best match first
+    pub fn search(&self, query: &VoiceQuery) -> Vec<&Voice> {
+        let mut ranked = self
+            .voice_list
+            .iter()
+            .filter_map(|voice| query.score(voice).map(|score| (score, voice)))
+            .collect::<Vec<_>>();
 
-        locales.into_iter().collect()
+        ranked.sort_by(|(a_score, a_voice), (b_score, b_voice)| {
+            b_score
+                .cmp(a_score)
+                .then_with(|| voice_short_name(a_voice).cmp(&voice_short_name(b_voice)))
+        });
+        ranked.into_iter().map(|(_, voice)| voice).collect()
     }
 
-    async fn filter_locale(&self, locale: impl AsRef<str>) -> Self {
-        let locale = locale.as_ref().to_lowercase();
+    // Like search, but only with the voices viewers are allowed to pick
+    pub fn search_allowed(&self, query: &VoiceQuery) -> Vec<&Voice> {
+        self.search(query)
+            .into_iter()
+            .filter(|voice| USER_DEFAULT_VOICE_CONFIG.is_voice_allowed(voice))
+            .collect()
+    }
 
+    // Keeps only the voices viewers are allowed to pick, see UserDefaultVoiceConfig
+    pub fn filter_allowed(&self) -> Self {
         let voice_list = self
             .voice_list
             .iter()
-            .filter(|voice| {
-                if let Some(v_locale) = &voice.locale {
-                    v_locale.to_lowercase().contains(locale.as_str())
-                } else {
-                    false
-                }
-            })
+            .filter(|voice| USER_DEFAULT_VOICE_CONFIG.is_voice_allowed(voice))
             .cloned()
             .collect::<Vec<_>>();
 
         if voice_list.is_empty() {
-            log_debug!("No voices found for locale: {}, no filter is applied", locale);
+            log_warning!("Voice allow/deny lists exclude every voice, no filter is applied");
             return self.clone();
         }
         Self { voice_list }
     }
 
-    async fn filter_gender(&self, gender: impl AsRef<str>) -> Self {
-        let gender = gender.as_ref().to_lowercase();
-
-        let voice_list = self
-            .voice_list
+    // Looks up a voice by its exact short name (it-IT-ElsaNeural) or full name
+    pub fn find_by_name(&self, name: impl AsRef<str>) -> Option<&Voice> {
+        let name = name.as_ref().to_lowercase();
+        self.voice_list
             .iter()
-            .filter(|voice| {
-                if let Some(v_gender) = &voice.gender {
-                    v_gender.to_lowercase().contains(gender.as_str())
-                } else {
-                    false
-                }
-            })
-            .cloned()
-            .collect::<Vec<_>>();
+            .find(|voice| voice_short_name(voice).to_lowercase() == name || voice.name.to_lowercase() == name)
+    }
 
-        if voice_list.is_empty() {
-            log_debug!("No correct gender found: {}, no filter is applied", gender);
-            return self.clone();
+    pub async fn list_all_locales(&self) -> Vec<String> {
+        let mut locales = HashSet::new();
+
+        // Collect unique locales into the HashSet
+        for voice in &self.voice_list {
+            if let Some(locale) = &voice.locale {
+                locales.insert(locale.clone()); // Insert clones to avoid ownership issues
+            }
         }
-        Self { voice_list }
+
+        locales.into_iter().collect()
     }
 
     pub fn random(&self) -> &Voice {
@@ -181,6 +359,193 @@ impl VoiceDB {
         &self.voice_list[index]
     }
 }
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VoiceQuery {
+    pub locale: Option<String>,
+    pub language: Option<String>,
+    pub region: Option<String>,
+    pub gender: Option<String>,
+    pub multilingual: Option<bool>,
+    pub names: Vec<String>,
+    pub engine: Option<String>,
+}
+
+impl VoiceQuery {
+    // Builds a query from free text tokens, e.g. ["it-IT", "female", "multi"] or ["gender:male", "name:diego"].
+    // Bare words are guessed: xx-YY is a locale, xx a language, XX a region, male/female a gender,
+    // multi/multilingual the multilingual flag, everything else a name substring.
+    pub fn parse(tokens: &[&str]) -> Self {
+        let mut query = Self::default();
+        for token in tokens.iter().map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            if let Some((key, value)) = token.split_once([':', '=']) {
+                let value = value.trim();
+                match key.to_lowercase().as_str() {
+                    "locale" => query.locale = Some(value.to_lowercase()),
+                    "lang" | "language" => query.language = Some(value.to_lowercase()),
+                    "region" | "country" => query.region = Some(value.to_lowercase()),
+                    "gender" => query.gender = Some(value.to_lowercase()),
+                    "multi" | "multilingual" => {
+                        query.multilingual =
+                            Some(!matches!(value.to_lowercase().as_str(), "false" | "no" | "0" | "off"))
+                    }
+                    "name" => query.names.push(value.to_lowercase()),
+                    "engine" => query.engine = Some(value.to_lowercase()),
+                    _ => query.names.push(token.to_lowercase()),
+                }
+                continue;
+            }
+
+            let lower = token.to_lowercase();
+            match lower.as_str() {
+                "male" | "female" | "neutral" => query.gender = Some(lower),
+                "multi" | "multilingual" => query.multilingual = Some(true),
+                _ if is_locale_tag(token) => query.locale = Some(lower),
+                _ if token.len() == 2 && token.chars().all(|c| c.is_ascii_lowercase()) => query.language = Some(lower),
+                _ if token.len() == 2 && token.chars().all(|c| c.is_ascii_uppercase()) => query.region = Some(lower),
+                _ => query.names.push(lower),
+            }
+        }
+        query
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // Returns None if the voice does not match every criterion, otherwise a ranking score, higher is better
+    pub fn score(&self, voice: &Voice) -> Option<u32> {
+        let locale = voice.locale.clone().unwrap_or_default().to_lowercase();
+        let short_name = voice_short_name(voice).to_lowercase();
+        let mut score = 0;
+
+        if let Some(wanted) = &self.locale {
+            if locale != *wanted {
+                return None;
+            }
+            score += 10;
+        }
+
+        if let Some(wanted) = &self.language {
+            if locale_language(&locale) != wanted.as_str() {
+                return None;
+            }
+            score += 5;
+        }
+
+        if let Some(wanted) = &self.region {
+            if locale_region(&locale) != wanted.as_str() {
+                return None;
+            }
+            score += 5;
+        }
+
+        if let Some(wanted) = &self.gender {
+            if voice.gender.clone().unwrap_or_default().to_lowercase() != *wanted {
+                return None;
+            }
+            score += 5;
+        }
+
+        if let Some(wanted) = self.multilingual {
+            if short_name.contains("multilingual") != wanted {
+                return None;
+            }
+            score += 5;
+        }
+
+        if let Some(wanted) = &self.engine {
+            if !short_name.ends_with(wanted.as_str()) {
+                return None;
+            }
+            score += 3;
+        }
+
+        let speaker = voice_speaker_name(voice).to_lowercase();
+        for name in &self.names {
+            score += if short_name == *name || speaker == *name {
+                20
+            } else if speaker.starts_with(name.as_str()) {
+                10
+            } else if short_name.contains(name.as_str()) {
+                5
+            } else {
+                return None;
+            };
+        }
+
+        Some(score)
+    }
+}
+
+impl std::fmt::Display for VoiceQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(locale) = &self.locale {
+            parts.push(format!("locale:{}", locale));
+        }
+        if let Some(language) = &self.language {
+            parts.push(format!("lang:{}", language));
+        }
+        if let Some(region) = &self.region {
+            parts.push(format!("region:{}", region));
+        }
+        if let Some(gender) = &self.gender {
+            parts.push(format!("gender:{}", gender));
+        }
+        if let Some(multilingual) = self.multilingual {
+            parts.push(format!("multi:{}", multilingual));
+        }
+        if let Some(engine) = &self.engine {
+            parts.push(format!("engine:{}", engine));
+        }
+        for name in &self.names {
+            parts.push(format!("name:{}", name));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+fn is_locale_tag(token: &str) -> bool {
+    let parts = token.split('-').collect::<Vec<_>>();
+    (2..=3).contains(&parts.len())
+        && parts[0].len() >= 2
+        && parts[0].len() <= 3
+        && parts
+            .iter()
+            .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_alphabetic()))
+}
+
+fn locale_language(locale: &str) -> &str {
+    locale.split('-').next().unwrap_or_default()
+}
+
+fn locale_region(locale: &str) -> &str {
+    // it-IT -> IT, sr-Latn-RS -> RS
+    locale
+        .rsplit('-')
+        .next()
+        .filter(|_| locale.contains('-'))
+        .unwrap_or_default()
+}
+
+// Short name as listed by Edge, e.g. it-IT-GiuseppeMultilingualNeural
+pub fn voice_short_name(voice: &Voice) -> String {
+    voice.short_name.clone().unwrap_or_else(|| voice.name.clone())
+}
+
+// Speaker part of the short name, e.g. GiuseppeMultilingualNeural
+fn voice_speaker_name(voice: &Voice) -> String {
+    let short_name = voice_short_name(voice);
+    match &voice.locale {
+        Some(locale) => short_name
+            .strip_prefix(&format!("{}-", locale))
+            .unwrap_or(&short_name)
+            .to_string(),
+        None => short_name,
+    }
+}
+
 pub async fn text_to_speech(message: TTSMassage) -> Result<()> {
     let text = remove_url_in_text(message.payload);
     let text = text
@@ -194,13 +559,32 @@ pub async fn text_to_speech(message: TTSMassage) -> Result<()> {
         })
         .collect::<String>();
 
+    let speech_config = SpeechConfig {
+        audio_format: TTS_AUDIO_FORMAT.into(),
+        ..message.speech_config.clone()
+    };
     let mut tts = msedge_tts::tts::client::connect_async().await?;
-    let audio = tts.synthesize(text.as_ref(), &message.speech_config).await?;
+    let audio = tts.synthesize(text.as_ref(), &speech_config).await?;
     if audio.audio_bytes.is_empty() {
         return Ok(());
     }
 
-    TTS_AUDIO_QUEUE.push_back(audio.audio_bytes).await;
+    match AudioClip::decode(audio.audio_bytes, message.source).await {
+        Ok(clip) => enqueue(clip).await,
+        Err(e) => {
+            log_error!(
+                "Unable to play the TTS audio, audio_format {}: {}",
+                speech_config.audio_format,
+                e
+            );
+            // The bot's own replies are already in chat
+            if !message.nick.is_empty() && message.nick != TWITCH_BOT_INFO.nick_name().await {
+                TWITCH_RECEIVER
+                    .send_privmsg(format!("@{}, your message could not be read out: {}", message.nick, e))
+                    .await;
+            }
+        }
+    }
 
     Ok(())
 }
@@ -212,30 +596,57 @@ fn remove_url_in_text(text: impl AsRef<str>) -> String {
 }
 
 pub async fn voice_msg(payload: &impl AsRef<str>, nick: &impl AsRef<str>) -> TTSMassage {
-    let speech_config = if nick.as_ref() != TWITCH_BOT_INFO.nick_name().await {
-        &USER_DB.write().await.get_user(nick).await.get_speech_config().clone()
+    let is_bot = nick.as_ref() == TWITCH_BOT_INFO.nick_name().await;
+    let speech_config = if !is_bot {
+        let user = USER_DB.write().await.get_user(nick).await;
+        &LANGUAGE_DETECTION
+            .speech_config_for(&user, payload)
+            .unwrap_or_else(|| user.get_speech_config().clone())
     } else {
         TWITCH_BOT_INFO.speech_config().await
     };
     TTSMassage {
         speech_config: speech_config.clone(),
         payload: payload.as_ref().into(),
+        source: if is_bot {
+            AudioSource::BotReply
+        } else {
+            AudioSource::ChatTts
+        },
+        nick: nick.as_ref().into(),
     }
 }
 
-pub async fn bot_cmd_tts_list_all_locales(_message: IrcMessage) -> Result<()> {
+// Builds the TTS message for a viewer's chat line, announcing the speaker
+pub async fn chat_voice_msg(message: &IrcMessage) -> TTSMassage {
+    let mut tts_message = voice_msg(&message.payload, &message.sender).await;
+    tts_message.payload = SPEAKER_ANNOUNCEMENT.announce(message).await;
+    tts_message
+}
+
+pub async fn bot_cmd_tts_list_all_locales(_message: IrcMessage, _args: CommandArgs) -> Result<()> {
     let ret_val = format!("Available locales: {}", TTS_VOCE_BD.list_all_locales().await.join(", "));
     TWITCH_RECEIVER.send_privmsg(ret_val).await;
     Ok(())
 }
 
-pub async fn bot_cmd_tts_reset_voice(message: IrcMessage) -> Result<()> {
+pub async fn bot_cmd_tts_reset_voice(message: IrcMessage, args: CommandArgs) -> Result<()> {
     let nick = message.sender;
-    let filter = &message.payload.split_whitespace().collect::<Vec<_>>()[1..];
+    if is_voice_locked(&nick).await {
+        return Ok(());
+    }
+    let filter = args
+        .text("filters")
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect::<Vec<_>>();
     USER_DB
         .write()
         .await
-        .update_user(&nick, (TTS_VOCE_BD.filter_voices_by_text(filter).random()).into())
+        .update_user(
+            &nick,
+            (TTS_VOCE_BD.filter_allowed().filter_voices_by_text(&filter).random()).into(),
+        )
         .await;
     let payload = format!(
         "@{}, your voice config has been updated to {}",
@@ -254,3 +665,432 @@ pub async fn bot_cmd_tts_reset_voice(message: IrcMessage) -> Result<()> {
     TWITCH_RECEIVER.send_privmsg(payload).await;
     Ok(())
 }
+
+pub async fn bot_cmd_tts_search_voices(message: IrcMessage, args: CommandArgs) -> Result<()> {
+    let mut terms = args
+        .text("query")
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect::<Vec<_>>();
+
+    // page=N or a trailing number selects the result page
+    let page = match args.integer("page") {
+        Some(page) => page as usize,
+        None => match terms.last().and_then(|term| term.parse::<usize>().ok()) {
+            Some(page) => {
+                terms.pop();
+                page.max(1)
+            }
+            None => 1,
+        },
+    };
+
+    let query = VoiceQuery::parse(&terms);
+    let voices = TTS_VOCE_BD.search_allowed(&query);
+    if voices.is_empty() {
+        TWITCH_RECEIVER
+            .send_privmsg(format!("@{}, no voices found for: {}", message.sender, query))
+            .await;
+        return Ok(());
+    }
+
+    let pages = voices.len().div_ceil(VOICES_PAGE_SIZE);
+    let page = page.min(pages);
+    let names = voices
+        .iter()
+        .skip((page - 1) * VOICES_PAGE_SIZE)
+        .take(VOICES_PAGE_SIZE)
+        .map(|voice| voice_short_name(voice))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let ret_val = format!(
+        "@{}, {} voices found (page {}/{}): {} | Use {}set_voice <name> to pick one",
+        message.sender,
+        voices.len(),
+        page,
+        pages,
+        names,
+        BOT_COMMANDS_CONFIG.prefix(&message.destination)
+    );
+    TWITCH_RECEIVER.send_privmsg(ret_val).await;
+    Ok(())
+}
+
+pub async fn bot_cmd_tts_set_voice(message: IrcMessage, args: CommandArgs) -> Result<()> {
+    let nick = message.sender;
+    let name = args.text("name").unwrap_or_default();
+
+    if is_voice_locked(&nick).await {
+        return Ok(());
+    }
+
+    let Some(voice) = find_allowed_voice(&nick, name).await else {
+        return Ok(());
+    };
+
+    USER_DB.write().await.update_user(&nick, voice.into()).await;
+    let payload = format!(
+        "@{}, your voice config has been updated to {}",
+        nick,
+        voice_short_name(voice)
+    );
+    TTS_QUEUE
+        .push_back(voice_msg(&payload, &TWITCH_BOT_INFO.nick_name().await).await)
+        .await;
+    TWITCH_RECEIVER.send_privmsg(payload).await;
+    Ok(())
+}
+
+// Looks up an exact voice name allowed by the allow/deny lists, replying in chat with close matches when it does
+// not exist or with a refusal when it is not allowed
+async fn find_allowed_voice(nick: &str, name: &str) -> Option<&'static Voice> {
+    if let Some(voice) = TTS_VOCE_BD.find_by_name(name) {
+        if USER_DEFAULT_VOICE_CONFIG.is_voice_allowed(voice) {
+            return Some(voice);
+        }
+        TWITCH_RECEIVER
+            .send_privmsg(format!("@{}, voice {} is not allowed", nick, voice_short_name(voice)))
+            .await;
+        return None;
+    }
+
+    let suggestions = TTS_VOCE_BD
+        .search_allowed(&VoiceQuery::parse(&[name]))
+        .iter()
+        .take(3)
+        .map(|voice| voice_short_name(voice))
+        .collect::<Vec<_>>();
+    let payload = if suggestions.is_empty() {
+        format!("@{}, no voice named {}", nick, name)
+    } else {
+        format!(
+            "@{}, no voice named {}, did you mean: {}",
+            nick,
+            name,
+            suggestions.join(", ")
+        )
+    };
+    TWITCH_RECEIVER.send_privmsg(payload).await;
+    None
+}
+
+// Parses values like "+10Hz", "-10%", "5" or "reset" into a signed offset
+fn parse_voice_offset(value: &str, unit: &str) -> Option<i32> {
+    let value = value.trim().to_lowercase();
+    if matches!(value.as_str(), "reset" | "default") {
+        return Some(0);
+    }
+    value.strip_suffix(unit).unwrap_or(&value).trim().parse::<i32>().ok()
+}
+
+fn describe_speech_config(speech_config: &SpeechConfig) -> String {
+    let voice_name = TTS_VOCE_BD
+        .find_by_name(&speech_config.voice_name)
+        .map(voice_short_name)
+        .unwrap_or_else(|| speech_config.voice_name.clone());
+    format!(
+        "voice {}, pitch {:+}Hz, rate {:+}%, volume {:+}%",
+        voice_name, speech_config.pitch, speech_config.rate, speech_config.volume
+    )
+}
+
+// !voice [show | set <name> | pitch <Hz> | rate <percent> | volume <percent> | announce | autolang | lang]
+pub async fn bot_cmd_tts_voice(message: IrcMessage, args: CommandArgs) -> Result<()> {
+    let nick = message.sender;
+    let limits = USER_DEFAULT_VOICE_CONFIG.limits();
+    let mut speech_config = USER_DB.write().await.get_user(&nick).await.get_speech_config().clone();
+
+    match args.subcommand().unwrap_or("show") {
+        "show" => {
+            TWITCH_RECEIVER
+                .send_privmsg(format!("@{}, {}", nick, describe_speech_config(&speech_config)))
+                .await;
+            return Ok(());
+        }
+        "announce" => {
+            let value = args.text("state").unwrap_or_default();
+            USER_DB.write().await.set_user_announce_name(&nick, value == "on").await;
+            TWITCH_RECEIVER
+                .send_privmsg(format!(
+                    "@{}, reading your name before your messages is now {}",
+                    nick, value
+                ))
+                .await;
+            return Ok(());
+        }
+        "autolang" => {
+            let value = args.text("state").unwrap_or_default();
+            USER_DB.write().await.set_user_auto_language(&nick, value == "on").await;
+            TWITCH_RECEIVER
+                .send_privmsg(format!("@{}, automatic language voice switch is now {}", nick, value))
+                .await;
+            return Ok(());
+        }
+        _ if is_voice_locked(&nick).await => return Ok(()),
+        "lang" => {
+            let language = args.text("language").unwrap_or_default();
+            let name = args.text("voice").unwrap_or_default();
+            if name.eq_ignore_ascii_case("reset") {
+                USER_DB
+                    .write()
+                    .await
+                    .set_user_language_voice(&nick, language, None)
+                    .await;
+                TWITCH_RECEIVER
+                    .send_privmsg(format!(
+                        "@{}, your voice for language {} has been reset",
+                        nick, language
+                    ))
+                    .await;
+                return Ok(());
+            }
+            let Some(voice) = find_allowed_voice(&nick, name).await else {
+                return Ok(());
+            };
+            USER_DB
+                .write()
+                .await
+                .set_user_language_voice(&nick, language, Some(voice.name.clone()))
+                .await;
+            TWITCH_RECEIVER
+                .send_privmsg(format!(
+                    "@{}, messages in language {} will be read with {}",
+                    nick,
+                    language,
+                    voice_short_name(voice)
+                ))
+                .await;
+            return Ok(());
+        }
+        "set" => {
+            let Some(voice) = find_allowed_voice(&nick, args.text("name").unwrap_or_default()).await else {
+                return Ok(());
+            };
+            speech_config.voice_name = voice.name.clone();
+        }
+        setting => {
+            let (unit, min, max) = match setting {
+                "pitch" => ("Hz", limits.pitch_min_hz, limits.pitch_max_hz),
+                "rate" => ("%", limits.rate_min_percent, limits.rate_max_percent),
+                _ => ("%", limits.volume_min_percent, limits.volume_max_percent),
+            };
+            let value = args.text("offset").unwrap_or_default();
+            let Some(offset) =
+                parse_voice_offset(value, &unit.to_lowercase()).filter(|offset| (min..=max).contains(offset))
+            else {
+                TWITCH_RECEIVER
+                    .send_privmsg(format!(
+                        "@{}, {} must be from {:+}{} to {:+}{}, or reset",
+                        nick, setting, min, unit, max, unit
+                    ))
+                    .await;
+                return Ok(());
+            };
+            match setting {
+                "pitch" => speech_config.pitch = offset,
+                "rate" => speech_config.rate = offset,
+                _ => speech_config.volume = offset,
+            }
+        }
+    }
+
+    let user = USER_DB.write().await.update_user(&nick, speech_config).await;
+    TWITCH_RECEIVER
+        .send_privmsg(format!(
+            "@{}, your voice config has been updated: {}",
+            nick,
+            describe_speech_config(user.get_speech_config())
+        ))
+        .await;
+
+    // Let the user hear the result in the new voice
+    TTS_QUEUE
+        .push_back(TTSMassage {
+            speech_config: user.get_speech_config().clone(),
+            payload: USER_DEFAULT_VOICE_CONFIG.preview_text().into(),
+            source: AudioSource::BotReply,
+            nick: nick.clone(),
+        })
+        .await;
+    Ok(())
+}
+
+// Subcommands of !voice, the offsets are checked against the configured limits by the handler
+fn voice_subcommands() -> Vec<Subcommand> {
+    let subcommand = |name, args| Subcommand {
+        name,
+        args,
+        ..Default::default()
+    };
+    let on_off = || vec![ArgSpec::required("state", ArgKind::Choice(&["on", "off"]))];
+    vec![
+        subcommand("show", vec![]),
+        subcommand("set", vec![ArgSpec::required("name", ArgKind::Word)]),
+        subcommand("pitch", vec![ArgSpec::required("offset", ArgKind::Word)]),
+        subcommand("rate", vec![ArgSpec::required("offset", ArgKind::Word)]),
+        subcommand("volume", vec![ArgSpec::required("offset", ArgKind::Word)]),
+        subcommand("announce", on_off()),
+        subcommand("autolang", on_off()),
+        subcommand("lang", vec![
+            ArgSpec::required("language", ArgKind::Word),
+            ArgSpec::required("voice", ArgKind::Word),
+        ]),
+    ]
+}
+
+// Replies and returns true when a moderator has locked the user's voice
+async fn is_voice_locked(nick: &str) -> bool {
+    if !USER_DB.write().await.get_user(nick).await.is_locked() {
+        return false;
+    }
+    TWITCH_RECEIVER
+        .send_privmsg(format!("@{}, your voice has been locked by a moderator", nick))
+        .await;
+    true
+}
+
+pub async fn bot_cmd_tts_set_user_voice(message: IrcMessage, args: CommandArgs) -> Result<()> {
+    let target = args.text("user").unwrap_or_default();
+    let name = args.text("voice").unwrap_or_default();
+    let Some(voice) = find_allowed_voice(&message.sender, name).await else {
+        return Ok(());
+    };
+    USER_DB.write().await.update_user(target, voice.into()).await;
+    TWITCH_RECEIVER
+        .send_privmsg(format!(
+            "@{}, voice of {} has been set to {}",
+            message.sender,
+            target,
+            voice_short_name(voice)
+        ))
+        .await;
+    Ok(())
+}
+
+pub async fn bot_cmd_tts_lock_voice(message: IrcMessage, args: CommandArgs) -> Result<()> {
+    let target = args.text("user").unwrap_or_default();
+
+    // Optionally fix the voice before locking it
+    if let Some(name) = args.text("voice") {
+        let Some(voice) = find_allowed_voice(&message.sender, name).await else {
+            return Ok(());
+        };
+        USER_DB.write().await.update_user(target, voice.into()).await;
+    }
+
+    let user = USER_DB.write().await.set_user_locked(target, true).await;
+    TWITCH_RECEIVER
+        .send_privmsg(format!(
+            "@{}, voice of {} is now locked: {}",
+            message.sender,
+            target,
+            describe_speech_config(user.get_speech_config())
+        ))
+        .await;
+    Ok(())
+}
+
+pub async fn bot_cmd_tts_unlock_voice(message: IrcMessage, args: CommandArgs) -> Result<()> {
+    let target = args.text("user").unwrap_or_default();
+
+    USER_DB.write().await.set_user_locked(target, false).await;
+    TWITCH_RECEIVER
+        .send_privmsg(format!("@{}, voice of {} is now unlocked", message.sender, target))
+        .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Built from the JSON of the Edge voice list, like get_voices_list
+    fn voice(short_name: &str, locale: &str, gender: &str) -> Voice {
+        serde_json::from_value(serde_json::json!({
+            "Name": format!("Microsoft Server Speech Text to Speech Voice ({})", short_name),
+            "ShortName": short_name,
+            "Gender": gender,
+            "Locale": locale,
+            "SuggestedCodec": "audio-24khz-48kbitrate-mono-mp3",
+            "FriendlyName": format!("Microsoft {} Online (Natural)", short_name),
+            "Status": "GA",
+            "VoiceTag": { "ContentCategories": ["General"], "VoicePersonalities": ["Friendly"] },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_guesses_the_bare_words() {
+        let query = VoiceQuery::parse(&["it-IT", "female", "multi", "elsa"]);
+        assert_eq!(query.locale.as_deref(), Some("it-it"));
+        assert_eq!(query.gender.as_deref(), Some("female"));
+        assert_eq!(query.multilingual, Some(true));
+        assert_eq!(query.names, vec!["elsa"]);
+
+        let query = VoiceQuery::parse(&["it", "IT"]);
+        assert_eq!(query.language.as_deref(), Some("it"));
+        assert_eq!(query.region.as_deref(), Some("it"));
+    }
+
+    #[test]
+    fn parse_reads_the_keys() {
+        let query = VoiceQuery::parse(&[
+            "lang:EN",
+            "country=GB",
+            "gender:Male",
+            "multi:no",
+            "name:Ryan",
+            "engine:neural",
+        ]);
+        assert_eq!(query.language.as_deref(), Some("en"));
+        assert_eq!(query.region.as_deref(), Some("gb"));
+        assert_eq!(query.gender.as_deref(), Some("male"));
+        assert_eq!(query.multilingual, Some(false));
+        assert_eq!(query.names, vec!["ryan"]);
+        assert_eq!(query.engine.as_deref(), Some("neural"));
+    }
+
+    #[test]
+    fn parse_keeps_unknown_keys_as_names() {
+        let query = VoiceQuery::parse(&["color:blue", " ", ""]);
+        assert_eq!(query.names, vec!["color:blue"]);
+        assert!(VoiceQuery::parse(&[]).is_empty());
+    }
+
+    #[test]
+    fn score_requires_every_criterion() {
+        let elsa = voice("it-IT-ElsaNeural", "it-IT", "Female");
+        assert!(VoiceQuery::parse(&["it-IT", "female"]).score(&elsa).is_some());
+        assert!(VoiceQuery::parse(&["it-IT", "male"]).score(&elsa).is_none());
+        assert!(VoiceQuery::parse(&["en"]).score(&elsa).is_none());
+        assert!(VoiceQuery::parse(&["multi"]).score(&elsa).is_none());
+        assert!(VoiceQuery::parse(&["diego"]).score(&elsa).is_none());
+    }
+
+    #[test]
+    fn score_ranks_exact_names_first() {
+        let giuseppe = voice("it-IT-GiuseppeMultilingualNeural", "it-IT", "Male");
+        let exact = VoiceQuery::parse(&["giuseppemultilingualneural"]).score(&giuseppe);
+        let prefix = VoiceQuery::parse(&["giuseppe"]).score(&giuseppe);
+        let substring = VoiceQuery::parse(&["neural"]).score(&giuseppe);
+        assert!(
+            exact > prefix && prefix > substring,
+            "{:?} {:?} {:?}",
+            exact,
+            prefix,
+            substring
+        );
+        assert!(substring.is_some());
+    }
+
+    #[test]
+    fn locale_helpers_split_the_tag() {
+        assert!(is_locale_tag("sr-Latn-RS"));
+        assert!(!is_locale_tag("it"));
+        assert!(!is_locale_tag("it-"));
+        assert_eq!(locale_language("sr-latn-rs"), "sr");
+        assert_eq!(locale_region("sr-latn-rs"), "rs");
+        assert_eq!(locale_region("it"), "");
+    }
+}
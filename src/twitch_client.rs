@@ -11,17 +11,19 @@ use tokio::sync::RwLock;
 use tokio_tungstenite::tungstenite::Message;
 
 use crate::CONFIG_DIR;
-use crate::bot_commands::BOT_COMMAND_PREFIX;
+use crate::bot_commands::BOT_COMMANDS_CONFIG;
 use crate::common::{BroadCastChannel, PersistentConfig};
 use crate::irc_parser::{IrcMessage, parse_message};
-use crate::tts::{TTS_QUEUE, TTS_VOCE_BD, voice_msg};
+use crate::tts::{TTS_QUEUE, TTS_VOCE_BD, chat_voice_msg};
 
 pub static TWITCH_BOT_INFO: LazyLock<TwitchBotInfo> = LazyLock::new(|| TwitchBotInfo::init());
 pub static TWITCH_BROADCAST: LazyLock<BroadCastChannel<IrcMessage>> =
     LazyLock::new(|| BroadCastChannel::<IrcMessage>::new(10));
 pub static TWITCH_RECEIVER: LazyLock<TwitchReceiver> = LazyLock::new(|| TwitchReceiver::new());
 
-static TWITCH_MAX_MSG_LINE_LENGTH: usize = 400;
+pub static TWITCH_MAX_MSG_LINE_LENGTH: usize = 400;
+// Notified by !die, the client then returns an error and gets restarted
+pub static TWITCH_DIE: LazyLock<tokio::sync::Notify> = LazyLock::new(|| tokio::sync::Notify::new());
 
 pub struct TwitchReceiver {
     queue: RwLock<VecDeque<String>>,
@@ -236,6 +238,10 @@ pub async fn start() -> Result<()> {
                 }
             }
 
+            _ = TWITCH_DIE.notified() => {
+                return Result::Err(anyhow!("I'm dying cruel world"));
+            }
+
             Some(ret_val) = TWITCH_RECEIVER.recv() => {
 
                     log_debug!("SENDING: {:?}", ret_val);
@@ -298,10 +304,6 @@ async fn handle_twitch_msg(text: impl AsRef<str>) -> Result<()> {
                 log_debug!("Replying to Server Ping");
                 TWITCH_RECEIVER.send_raw(format!("PONG :{}", line.payload)).await;
             }
-            "PRIVMSG" if line.payload == "!die" => {
-                log_error!("I'm dying cruel world");
-                return Result::Err(anyhow!("I'm dying cruel world"));
-            }
             "001" => {
                 // First reply, you can use destination as bot NickName
                 log!("Bot NickName is: {}", line.destination);
@@ -314,8 +316,13 @@ async fn handle_twitch_msg(text: impl AsRef<str>) -> Result<()> {
             }
             "PRIVMSG" => {
                 TWITCH_BROADCAST.send_broadcast(line.clone()).await?;
-                if !line.payload.starts_with(BOT_COMMAND_PREFIX) {
-                    TTS_QUEUE.push_back(voice_msg(&line.payload, &line.sender).await).await;
+                // Commands are not read out
+                if BOT_COMMANDS_CONFIG
+                    .command_line(&line.destination, &line.payload)
+                    .await
+                    .is_none()
+                {
+                    TTS_QUEUE.push_back(chat_voice_msg(&line).await).await;
                 }
             }
             "PONG" => {
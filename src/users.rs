@@ -3,12 +3,13 @@ use std::sync::LazyLock;
 
 use futures::executor::block_on;
 use msedge_tts::tts::SpeechConfig;
+use msedge_tts::voice::Voice;
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 use crate::CONFIG_DIR;
 use crate::common::PersistentConfig;
-use crate::tts::TTS_VOCE_BD;
+use crate::tts::{TTS_VOCE_BD, voice_short_name};
 
 pub static USER_DB: LazyLock<RwLock<UsersDB>> = LazyLock::new(|| RwLock::new(UsersDB::init(CONFIG_DIR)));
 pub static USER_DEFAULT_VOICE_CONFIG: LazyLock<UserDefaultVoiceConfig> =
@@ -37,12 +38,46 @@ impl UsersDB {
     }
 
     pub async fn update_user(&mut self, nick: impl AsRef<str>, speech_config: SpeechConfig) -> User {
-        self.users.insert(nick.as_ref().into(), User {
-            nick: nick.as_ref().into(),
-            speech_config,
-        });
-        let _ = (*self).save(CONFIG_DIR).await;
-        self.get_user(nick).await
+        self.modify_user(nick, |user| user.speech_config = speech_config).await
+    }
+
+    pub async fn set_user_auto_language(&mut self, nick: impl AsRef<str>, auto_language: bool) -> User {
+        self.modify_user(nick, |user| user.auto_language = auto_language).await
+    }
+
+    pub async fn set_user_announce_name(&mut self, nick: impl AsRef<str>, announce_name: bool) -> User {
+        self.modify_user(nick, |user| user.announce_name = announce_name).await
+    }
+
+    // Passing None removes the user's voice for that language
+    pub async fn set_user_language_voice(
+        &mut self,
+        nick: impl AsRef<str>,
+        language: impl AsRef<str>,
+        voice_name: Option<String>,
+    ) -> User {
+        let language = language.as_ref().to_lowercase();
+        self.modify_user(nick, |user| {
+            match voice_name {
+                Some(voice_name) => user.language_voices.insert(language, voice_name),
+                None => user.language_voices.remove(&language),
+            };
+        })
+        .await
+    }
+
+    // Locked users can not change their own voice, only moderators can
+    pub async fn set_user_locked(&mut self, nick: impl AsRef<str>, locked: bool) -> User {
+        self.modify_user(nick, |user| user.locked = locked).await
+    }
+
+    // Applies the change to the user, created when missing, and saves the database
+    async fn modify_user(&mut self, nick: impl AsRef<str>, change: impl FnOnce(&mut User)) -> User {
+        let mut user = self.get_user(&nick).await;
+        change(&mut user);
+        self.users.insert(nick.as_ref().into(), user.clone());
+        self.save(CONFIG_DIR).await;
+        user
     }
 
     // This will return if user exist in db or generate new user
@@ -59,6 +94,25 @@ impl UsersDB {
 pub struct User {
     nick: String,
     speech_config: SpeechConfig,
+    #[serde(default)]
+    locked: bool,
+    // Opt-out of the automatic language detection voice switch
+    #[serde(default = "default_auto_language")]
+    auto_language: bool,
+    // Language code -> voice name used when a message is detected in that language
+    #[serde(default)]
+    language_voices: HashMap<String, String>,
+    // Opt-out of the speaker name read before the message
+    #[serde(default = "default_announce_name")]
+    announce_name: bool,
+}
+
+fn default_announce_name() -> bool {
+    true
+}
+
+fn default_auto_language() -> bool {
+    true
 }
 
 impl Default for User {
@@ -66,6 +120,10 @@ impl Default for User {
         Self {
             nick: "default".into(),
             speech_config: TTS_VOCE_BD.random().into(),
+            locked: false,
+            auto_language: default_auto_language(),
+            language_voices: HashMap::new(),
+            announce_name: default_announce_name(),
         }
     }
 }
@@ -75,6 +133,7 @@ impl User {
         Self {
             nick: nick.as_ref().into(),
             speech_config: TTS_VOCE_BD
+                .filter_allowed()
                 .filter_voices_by_text(&[USER_DEFAULT_VOICE_CONFIG
                     .filter
                     .clone()
@@ -82,23 +141,86 @@ impl User {
                     .as_str()])
                 .random()
                 .into(),
+            locked: false,
+            auto_language: default_auto_language(),
+            language_voices: HashMap::new(),
+            announce_name: default_announce_name(),
         }
     }
 
     pub fn get_speech_config(&self) -> &SpeechConfig {
         &self.speech_config
     }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn auto_language(&self) -> bool {
+        self.auto_language
+    }
+
+    pub fn announce_name(&self) -> bool {
+        self.announce_name
+    }
+
+    pub fn language_voice(&self, language: impl AsRef<str>) -> Option<&String> {
+        self.language_voices.get(language.as_ref())
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct UserDefaultVoiceConfig {
     filter: Option<String>,
+    #[serde(default)]
+    limits: VoiceLimits,
+    #[serde(default = "default_preview_text")]
+    preview_text: String,
+    // Voice short names viewers may pick, empty means all voices
+    #[serde(default)]
+    allowed_voices: Vec<String>,
+    // Voice short names nobody may pick
+    #[serde(default)]
+    denied_voices: Vec<String>,
 }
 
 impl Default for UserDefaultVoiceConfig {
     fn default() -> Self {
         Self {
             filter: Some("multilingual".into()),
+            limits: VoiceLimits::default(),
+            preview_text: default_preview_text(),
+            allowed_voices: Vec::new(),
+            denied_voices: Vec::new(),
+        }
+    }
+}
+
+fn default_preview_text() -> String {
+    "Hi, this is how my new voice sounds".into()
+}
+
+// Bounds the streamer allows viewers to use with the !voice command
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct VoiceLimits {
+    pub pitch_min_hz: i32,
+    pub pitch_max_hz: i32,
+    pub rate_min_percent: i32,
+    pub rate_max_percent: i32,
+    pub volume_min_percent: i32,
+    pub volume_max_percent: i32,
+}
+
+impl Default for VoiceLimits {
+    fn default() -> Self {
+        Self {
+            pitch_min_hz: -50,
+            pitch_max_hz: 50,
+            rate_min_percent: -50,
+            rate_max_percent: 100,
+            volume_min_percent: -50,
+            volume_max_percent: 50,
         }
     }
 }
@@ -111,4 +233,20 @@ impl UserDefaultVoiceConfig {
     }
 
     pub fn warm_up(&self) {}
+
+    pub fn limits(&self) -> &VoiceLimits {
+        &self.limits
+    }
+
+    pub fn preview_text(&self) -> &str {
+        &self.preview_text
+    }
+
+    pub fn is_voice_allowed(&self, voice: &Voice) -> bool {
+        let matches = |name: &String| {
+            name.eq_ignore_ascii_case(&voice_short_name(voice)) || name.eq_ignore_ascii_case(&voice.name)
+        };
+        !self.denied_voices.iter().any(matches)
+            && (self.allowed_voices.is_empty() || self.allowed_voices.iter().any(matches))
+    }
 }
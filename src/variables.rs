@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::LazyLock;
+
+use eyre::{Result, eyre};
+use futures::executor::block_on;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::CONFIG_DIR;
+use crate::command_args::CommandArgs;
+use crate::common::PersistentConfig;
+use crate::irc_parser::IrcMessage;
+use crate::twitch_client::TWITCH_RECEIVER;
+
+pub static VARIABLES: LazyLock<RwLock<Variables>> = LazyLock::new(|| RwLock::new(Variables::init(CONFIG_DIR)));
+
+pub const VARIABLE_USAGE: &str = "<name> [inc [amount] | dec [amount] | set <value> | reset]";
+
+// A counter, or a text when the value is not a whole number
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum VariableValue {
+    Integer(i64),
+    Text(String),
+}
+
+impl VariableValue {
+    fn parse(value: &str) -> Self {
+        match value.parse::<i64>() {
+            Ok(value) => VariableValue::Integer(value),
+            Err(_) => VariableValue::Text(value.to_string()),
+        }
+    }
+}
+
+impl Display for VariableValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VariableValue::Integer(value) => write!(f, "{}", value),
+            VariableValue::Text(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+// Values kept across restarts, read by the replies with {VAR:name}
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Variables {
+    values: BTreeMap<String, VariableValue>,
+}
+
+impl PersistentConfig for Variables {}
+
+impl Variables {
+    pub fn init(config_dir: Option<&str>) -> Variables {
+        block_on(Variables::load(config_dir))
+    }
+
+    // This will be called on bot start to load the variables
+    pub fn warm_up(&self) {}
+
+    pub fn get(&self, name: &str) -> Option<&VariableValue> {
+        self.values.get(&name.to_lowercase())
+    }
+
+    // Adds to a counter, a missing one starts from 0
+    pub async fn add(&mut self, name: &str, amount: i64) -> Result<i64> {
+        let name = checked_name(name)?;
+        let value = match self.values.get(&name) {
+            None => amount,
+            Some(VariableValue::Integer(value)) => value
+                .checked_add(amount)
+                .ok_or_else(|| eyre!("{} can't go past {}", name, value))?,
+            Some(VariableValue::Text(_)) => return Err(eyre!("{} is not a counter", name)),
+        };
+        self.values.insert(name, VariableValue::Integer(value));
+        self.save(CONFIG_DIR).await;
+        Ok(value)
+    }
+
+    pub async fn set(&mut self, name: &str, value: &str) -> Result<VariableValue> {
+        let name = checked_name(name)?;
+        let value = VariableValue::parse(value.trim());
+        self.values.insert(name, value.clone());
+        self.save(CONFIG_DIR).await;
+        Ok(value)
+    }
+
+    // Removes the variable, so a counter starts again from 0
+    pub async fn reset(&mut self, name: &str) -> bool {
+        let removed = self.values.remove(&name.to_lowercase()).is_some();
+        if removed {
+            self.save(CONFIG_DIR).await;
+        }
+        removed
+    }
+}
+
+// Letters, digits and _, case insensitive
+pub fn checked_name(name: &str) -> Result<String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(eyre!("{} is not a valid variable name", name));
+    }
+    Ok(name.to_lowercase())
+}
+
+// !var <name> [inc|dec|set|reset], shows or changes a variable
+pub async fn bot_cmd_variable(message: IrcMessage, args: CommandArgs) -> Result<()> {
+    let name = args.text("name").unwrap_or_default();
+    let reply = match edit_variable(name, args.text("action"), args.text("value")).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            log_debug!("!var {} failed: {}", name, e);
+            e.to_string()
+        }
+    };
+    TWITCH_RECEIVER
+        .send_privmsg(format!("@{}, {}", message.sender, reply))
+        .await;
+    Ok(())
+}
+
+async fn edit_variable(name: &str, action: Option<&str>, value: Option<&str>) -> Result<String> {
+    let amount = || -> Result<i64> {
+        value.map_or(Ok(1), |amount| {
+            amount
+                .trim()
+                .parse::<i64>()
+                .map_err(|_| eyre!("amount must be a whole number"))
+        })
+    };
+    let mut variables = VARIABLES.write().await;
+    let reply = match (action, value) {
+        (None, _) => match variables.get(name) {
+            Some(value) => format!("{} is {}", name, value),
+            None => format!("{} is not set", name),
+        },
+        (Some("inc"), _) => format!("{} is now {}", name, variables.add(name, amount()?).await?),
+        (Some("dec"), _) => {
+            let amount = amount()?.checked_neg().ok_or_else(|| eyre!("amount is too large"))?;
+            format!("{} is now {}", name, variables.add(name, amount).await?)
+        }
+        (Some("set"), Some(value)) => format!("{} is now {}", name, variables.set(name, value).await?),
+        (Some("reset"), None) => {
+            if variables.reset(name).await {
+                format!("{} has been reset", name)
+            } else {
+                format!("{} is not set", name)
+            }
+        }
+        _ => return Err(eyre!("usage: {}", VARIABLE_USAGE)),
+    };
+    Ok(reply)
+}